@@ -0,0 +1,98 @@
+/*
+
+Accounts.json, the local cache of accounts that have been used on this machine, for quick sign in.
+It lives in the data directory (see keystore.rs) and only holds public information plus the password-encrypted private key.
+
+The cache is never trusted on its own; every cached account is re-checked against the account database before it's used.
+
+*/
+
+use super::{keystore, structs::Account};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CachedAccount
+{
+    pub id: String,
+    pub username: String,
+    pub public_key: Vec<u8>,
+    pub priv_key_enc: Vec<u8>
+}
+
+impl CachedAccount
+{
+    fn from_account(account: &Account) -> CachedAccount
+    {
+        CachedAccount {
+            id: account.id.clone(),
+            username: account.username.clone(),
+            public_key: account.public_key.clone(),
+            priv_key_enc: account.priv_key_enc.clone()
+        }
+    }
+}
+
+fn cache_path() -> PathBuf { keystore::data_dir().join("Accounts.json") }
+
+/// Loads the cached accounts. A missing or unreadable cache is treated as empty.
+pub fn load() -> Vec<CachedAccount>
+{
+    fs::read(cache_path())
+        .ok()
+        .and_then(|x| serde_json::from_slice(&x).ok())
+        .unwrap_or_default()
+}
+
+fn save(accounts: &[CachedAccount])
+{
+    let json: Vec<u8> = serde_json::to_vec_pretty(accounts).unwrap();
+    keystore::write_private_file(&cache_path(), &json).expect("Failed to write Accounts.json.");
+}
+
+/// Adds an account to the cache, or refreshes its entry if it's already there.
+pub fn remember(account: &Account)
+{
+    let mut accounts: Vec<CachedAccount> = load();
+    match accounts.iter_mut().find(|x| x.id == account.id)
+    {
+        Some(cached) => *cached = CachedAccount::from_account(account),
+        None => accounts.push(CachedAccount::from_account(account))
+    }
+    save(&accounts);
+}
+
+/// Removes an account from the cache.
+pub fn forget(id: &str)
+{
+    let mut accounts: Vec<CachedAccount> = load();
+    accounts.retain(|x| x.id != id);
+    save(&accounts);
+}
+
+/// Checks a cached account against the account database, and returns the current account if it's still valid.
+///
+/// Accounts that no longer exist, or whose public key no longer matches the cached one, are dropped from the cache and an error is returned;
+/// those have to be signed into with a username again. Anything else that changed (username, or the encrypted key after a password change) is refreshed.
+pub fn revalidate(cached: &CachedAccount) -> Result<Account, String>
+{
+    match Account::get_account_by_id(&cached.id)
+    {
+        Some(account) if account.public_key == cached.public_key =>
+        {
+            remember(&account);
+            Ok(account)
+        }
+        Some(_) =>
+        {
+            forget(&cached.id);
+            Err(format!("The keys for {} have changed since it was last used here. Please log in with your username.", cached.username))
+        }
+        None =>
+        {
+            forget(&cached.id);
+            Err(format!("The account {} no longer exists.", cached.username))
+        }
+    }
+}
@@ -0,0 +1,208 @@
+/*
+
+Public key operations, for both kinds of keys an account can have.
+
+Accounts made before the upgrade use RSA-2048 for everything. Newer accounts (and any account that's been upgraded from the manage account page)
+use X25519 for encryption and Ed25519 for signing. Only the X25519 key is stored; the Ed25519 key is derived from it, so the password
+and recovery copies of the private key work the same way for both kinds of account.
+
+Conversation keys are wrapped for a device (or identity) key according to whatever kind of key it is:
+RSA keys use PKCS#1 padding, as before. X25519 keys get a fresh ephemeral X25519 key; the shared secret (hashed along with the ephemeral public key)
+is used as an AES-256-GCM key, and the wrapped key is the ephemeral public key, the nonce, the tag and the ciphertext, in that order.
+
+*/
+
+use getrandom::getrandom;
+use openssl::{
+    derive::Deriver, pkey::{Id, PKey, Private, Public}, rsa::Padding, sha::sha256, sign::{Signer, Verifier}, symm::{self, Cipher}
+};
+
+/// The original algorithm: RSA-2048 for everything.
+pub const ALGORITHM_RSA: &str = "rsa-2048";
+/// X25519 for encryption, with an Ed25519 signing key derived from it.
+pub const ALGORITHM_CURVE25519: &str = "x25519-ed25519";
+
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+const X25519_LENGTH: usize = 32;
+
+/// Generates a new X25519 private key, used for both identity and device keys.
+pub fn generate_key() -> PKey<Private> { PKey::generate_x25519().expect("Failed to generate key.") }
+
+/// Derives the Ed25519 signing key that goes with an X25519 identity key.
+pub fn signing_key(identity: &PKey<Private>) -> PKey<Private>
+{
+    let mut seed: Vec<u8> = b"crim signing key".to_vec();
+    seed.extend(identity.raw_private_key().expect("Only X25519 identity keys have a signing key."));
+    PKey::private_key_from_raw_bytes(&sha256(&seed), Id::ED25519).expect("Failed to derive signing key.")
+}
+
+/// Signs some data with the signing key that goes with an X25519 identity key.
+pub fn sign(identity: &PKey<Private>, data: &[u8]) -> Vec<u8> { sign_with(&signing_key(identity), data) }
+
+/// Signs some data with an Ed25519 key.
+pub fn sign_with(key: &PKey<Private>, data: &[u8]) -> Vec<u8>
+{
+    let mut signer: Signer = Signer::new_without_digest(key).expect("Failed to create signer.");
+    signer.sign_oneshot_to_vec(data).expect("Failed to sign.")
+}
+
+/// Checks an Ed25519 signature against a PEM-encoded public signing key.
+pub fn verify_signature(signing_key: &[u8], data: &[u8], signature: &[u8]) -> bool
+{
+    let key: PKey<Public> = match PKey::public_key_from_pem(signing_key)
+    {
+        Ok(key) if key.id() == Id::ED25519 => key,
+        _ => return false
+    };
+    Verifier::new_without_digest(&key)
+        .and_then(|mut x| x.verify_oneshot(signature, data))
+        .unwrap_or(false)
+}
+
+/// Encrypts a conversation key for a PEM-encoded public key. Fails if the public key can't be read.
+pub fn wrap_key(public_key: &[u8], key: &[u8]) -> Result<Vec<u8>, String>
+{
+    let public_key: PKey<Public> = PKey::public_key_from_pem(public_key).map_err(|_| "A public key in the database couldn't be read.".to_string())?;
+    match public_key.id()
+    {
+        Id::X25519 =>
+        {
+            let ephemeral: PKey<Private> = generate_key();
+            let ephemeral_public: Vec<u8> = ephemeral.raw_public_key().unwrap();
+            let secret: [u8; 32] = shared_secret(&ephemeral, &public_key, &ephemeral_public);
+            let mut nonce: [u8; NONCE_LENGTH] = [0; NONCE_LENGTH];
+            getrandom(&mut nonce).expect("Failed to generate nonce.");
+            let mut tag: [u8; TAG_LENGTH] = [0; TAG_LENGTH];
+            let ciphertext: Vec<u8> = symm::encrypt_aead(Cipher::aes_256_gcm(), &secret, Some(&nonce), &[], key, &mut tag).map_err(|e| e.to_string())?;
+            Ok([ephemeral_public, nonce.to_vec(), tag.to_vec(), ciphertext].concat())
+        }
+        _ =>
+        {
+            let public_key = public_key.rsa().map_err(|_| "A public key in the database isn't a kind that can be encrypted for.".to_string())?;
+            let mut encrypted_key: Vec<u8> = vec![0; public_key.size() as usize];
+            let length: usize = public_key
+                .public_encrypt(key, &mut encrypted_key, Padding::PKCS1)
+                .map_err(|e| e.to_string())?;
+            encrypted_key.truncate(length);
+            Ok(encrypted_key)
+        }
+    }
+}
+
+/// Decrypts a conversation key that was encrypted with the public half of `private_key`.
+/// Fails if the wrapped key is malformed, or was wrapped for a different key.
+pub fn unwrap_key(private_key: &PKey<Private>, encrypted_key: &[u8]) -> Result<Vec<u8>, String>
+{
+    match private_key.id()
+    {
+        Id::X25519 =>
+        {
+            if encrypted_key.len() < X25519_LENGTH + NONCE_LENGTH + TAG_LENGTH
+            {
+                return Err("The conversation key is too short to have been wrapped for this key.".to_string());
+            }
+            let (ephemeral_public, rest) = encrypted_key.split_at(X25519_LENGTH);
+            let (nonce, rest) = rest.split_at(NONCE_LENGTH);
+            let (tag, ciphertext) = rest.split_at(TAG_LENGTH);
+            let ephemeral: PKey<Public> = PKey::public_key_from_raw_bytes(ephemeral_public, Id::X25519).map_err(|e| e.to_string())?;
+            let secret: [u8; 32] = shared_secret(private_key, &ephemeral, ephemeral_public);
+            symm::decrypt_aead(Cipher::aes_256_gcm(), &secret, Some(nonce), &[], ciphertext, tag)
+                .map_err(|_| "Failed to decrypt the conversation key.".to_string())
+        }
+        _ =>
+        {
+            let private_key = private_key.rsa().map_err(|e| e.to_string())?;
+            let mut decrypted_key: Vec<u8> = vec![0; private_key.size() as usize];
+            let length: usize = private_key
+                .private_decrypt(encrypted_key, &mut decrypted_key, Padding::PKCS1)
+                .map_err(|_| "Failed to decrypt the conversation key.".to_string())?;
+            decrypted_key.truncate(length);
+            Ok(decrypted_key)
+        }
+    }
+}
+
+/// The AES key for an X25519 wrap: the Diffie-Hellman secret between `private_key` and `public_key`, hashed with the ephemeral public key.
+/// The same from either side: (ephemeral private, recipient public) when wrapping, (recipient private, ephemeral public) when unwrapping.
+fn shared_secret<T>(private_key: &PKey<Private>, public_key: &PKey<T>, ephemeral_public: &[u8]) -> [u8; 32]
+where
+    T: openssl::pkey::HasPublic
+{
+    let mut deriver: Deriver = Deriver::new(private_key).expect("failed to derive key");
+    deriver.set_peer(public_key).expect("failed to derive key");
+    let mut material: Vec<u8> = deriver.derive_to_vec().expect("failed to derive key");
+    material.extend_from_slice(ephemeral_public);
+    sha256(&material)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use openssl::rsa::Rsa;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    #[test]
+    fn x25519_keys_round_trip()
+    {
+        let private_key: PKey<Private> = generate_key();
+        let wrapped: Vec<u8> = wrap_key(&private_key.public_key_to_pem().unwrap(), &KEY).unwrap();
+        assert_eq!(wrapped.len(), X25519_LENGTH + NONCE_LENGTH + TAG_LENGTH + KEY.len());
+        assert_eq!(unwrap_key(&private_key, &wrapped), Ok(KEY.to_vec()));
+    }
+
+    #[test]
+    fn rsa_keys_round_trip()
+    {
+        let private_key: PKey<Private> = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let wrapped: Vec<u8> = wrap_key(&private_key.public_key_to_pem().unwrap(), &KEY).unwrap();
+        assert_eq!(unwrap_key(&private_key, &wrapped), Ok(KEY.to_vec()));
+    }
+
+    #[test]
+    fn short_keys_are_an_error()
+    {
+        let private_key: PKey<Private> = generate_key();
+        assert!(unwrap_key(&private_key, &[]).is_err());
+        assert!(unwrap_key(&private_key, &[1; X25519_LENGTH + NONCE_LENGTH]).is_err());
+    }
+
+    #[test]
+    fn tampered_keys_are_an_error()
+    {
+        let private_key: PKey<Private> = generate_key();
+        let mut wrapped: Vec<u8> = wrap_key(&private_key.public_key_to_pem().unwrap(), &KEY).unwrap();
+        let last: usize = wrapped.len() - 1;
+        wrapped[last] ^= 1;
+        assert!(unwrap_key(&private_key, &wrapped).is_err());
+    }
+
+    #[test]
+    fn keys_wrapped_for_someone_else_are_an_error()
+    {
+        let wrapped: Vec<u8> = wrap_key(&generate_key().public_key_to_pem().unwrap(), &KEY).unwrap();
+        assert!(unwrap_key(&generate_key(), &wrapped).is_err());
+    }
+
+    #[test]
+    fn unreadable_public_keys_are_an_error()
+    {
+        assert!(wrap_key(b"not a key", &KEY).is_err());
+        // a signing key can't be encrypted for
+        let signing_key: Vec<u8> = signing_key(&generate_key()).public_key_to_pem().unwrap();
+        assert!(wrap_key(&signing_key, &KEY).is_err());
+    }
+
+    #[test]
+    fn signatures_verify()
+    {
+        let identity: PKey<Private> = generate_key();
+        let signing_key: Vec<u8> = signing_key(&identity).public_key_to_pem().unwrap();
+        let signature: Vec<u8> = sign(&identity, b"device key");
+        assert!(verify_signature(&signing_key, b"device key", &signature));
+        assert!(!verify_signature(&signing_key, b"another key", &signature));
+        assert!(!verify_signature(b"not a key", b"device key", &signature));
+    }
+}
@@ -0,0 +1,112 @@
+/*
+
+Key fingerprints and safety numbers, so users can check the keys their messages are encrypted to haven't been swapped out by whoever runs the database.
+
+An account's fingerprint covers its identity and signing keys and the keys of its approved devices, since conversation keys are encrypted for each device.
+That means approving a new device changes the fingerprint too, which is on purpose: a device nobody approved is exactly what a swapped key looks like.
+
+A safety number combines two accounts' fingerprints, and is the same on both ends. Comparing it in person (or over a call) proves both sides see the same keys.
+Contacts whose safety number has been compared can be marked as verified. That's stored locally, per account, in verified/<account id>.json in the data directory,
+along with the fingerprint that was verified, so a later key change can be spotted.
+
+*/
+
+use super::{keystore, structs::Account};
+use openssl::sha::sha256;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Where a contact stands, as far as this account is concerned.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Verification
+{
+    Unverified,
+    Verified,
+    /// The contact was verified, but their keys have changed since.
+    Changed
+}
+
+/// The raw fingerprint of an account: a SHA-256 hash of its ID, identity key, signing key, and approved device keys (sorted by device ID, so the order they're stored in doesn't matter).
+fn digest(account: &Account) -> [u8; 32]
+{
+    let mut devices: Vec<_> = account.devices.iter().filter(|x| x.approved).collect();
+    devices.sort_by(|a, b| a.id.cmp(&b.id));
+    let mut material: Vec<u8> = Vec::new();
+    material.extend_from_slice(account.id.as_bytes());
+    material.extend_from_slice(&account.public_key);
+    material.extend_from_slice(&account.signing_key);
+    for device in devices
+    {
+        material.extend_from_slice(device.id.as_bytes());
+        material.extend_from_slice(&device.public_key);
+    }
+    sha256(&material)
+}
+
+/// An account's fingerprint, as shown to the user: 64 hex digits in groups of four.
+pub fn fingerprint(account: &Account) -> String
+{
+    hex::encode_upper(digest(account))
+        .as_bytes()
+        .chunks(4)
+        .map(|x| String::from_utf8(x.to_vec()).unwrap())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// The safety number for a pair of accounts: 60 digits in groups of five. Each account contributes half, and the halves are ordered by account ID so both sides get the same number.
+pub fn safety_number(a: &Account, b: &Account) -> String
+{
+    let (first, second) = if a.id <= b.id { (a, b) } else { (b, a) };
+    [digest(first), digest(second)]
+        .iter()
+        .flat_map(|x| x.chunks(5).take(6).map(|y| y.iter().fold(0_u64, |acc, z| (acc << 8) | *z as u64) % 100000).collect::<Vec<u64>>())
+        .map(|x| format!("{:05}", x))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn verified_path(account_id: &str) -> PathBuf { keystore::data_dir().join("verified").join(format!("{}.json", account_id)) }
+
+/// Loads the contacts an account has verified, as contact ID -> the fingerprint that was verified. A missing or unreadable file is treated as empty.
+fn load(account_id: &str) -> HashMap<String, String>
+{
+    fs::read(verified_path(account_id))
+        .ok()
+        .and_then(|x| serde_json::from_slice(&x).ok())
+        .unwrap_or_default()
+}
+
+fn save(account_id: &str, verified: &HashMap<String, String>)
+{
+    let json: Vec<u8> = serde_json::to_vec_pretty(verified).unwrap();
+    keystore::write_private_file(&verified_path(account_id), &json).expect("Failed to save verified contacts.");
+}
+
+/// Checks a contact's current keys against the ones the account verified, if any.
+pub fn status(account_id: &str, contact: &Account) -> Verification
+{
+    match load(account_id).get(&contact.id)
+    {
+        None => Verification::Unverified,
+        Some(verified) if *verified == fingerprint(contact) => Verification::Verified,
+        Some(_) => Verification::Changed
+    }
+}
+
+/// Marks a contact as verified with their current keys.
+pub fn verify(account_id: &str, contact: &Account)
+{
+    let mut verified: HashMap<String, String> = load(account_id);
+    verified.insert(contact.id.clone(), fingerprint(contact));
+    save(account_id, &verified);
+}
+
+/// Removes a contact's verification.
+pub fn unverify(account_id: &str, contact_id: &str)
+{
+    let mut verified: HashMap<String, String> = load(account_id);
+    verified.remove(contact_id);
+    save(account_id, &verified);
+}
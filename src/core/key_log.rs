@@ -0,0 +1,703 @@
+/*
+
+The key transparency log: an append-only record of every public key an account has published, kept in the "key_log" collection next to the accounts.
+
+Every identity key, device key and Ed25519 signing key is appended when it's published, and a device that's removed gets an entry with an empty key.
+The entries are the leaves of a Merkle tree (built the same way as Certificate Transparency, RFC 6962), so the whole log is summed up by one root hash.
+
+Tree heads (a size and the root of the first that many entries) are signed with the log key, KEY_LOG_KEY in the .env file, and kept in "key_log_heads".
+The database never has the log key, so it can't sign a head for a log it's rewritten. Clients sign a new head whenever they find entries that aren't
+covered by one yet, but only once the entries that are covered check out against the latest signed head.
+
+The client pins the last signed head it accepted, in key_log.json in the data directory. A newer head is only accepted with a consistency proof
+from the pinned one, i.e. if the log has been appended to rather than rewritten. Before a conversation key is encrypted for someone, each of their keys
+has to be the latest entry for that key, and the entry has to be in the pinned tree, by an audit path from it to the pinned root.
+Swapping a key without anyone noticing means adding it to the log, where `crim audit-keys` will show it forever.
+
+The log is read once per run and kept in memory; after that only entries appended since are fetched, and proofs are only worked out again when the head moves.
+
+*/
+
+use super::{crypto, keystore, mongo, structs::Account};
+use mongodb::{
+    bson::{self, doc, Document}, error::{Error, ErrorKind, WriteFailure}, options::{FindOneOptions, FindOptions, IndexOptions}, IndexModel
+};
+use openssl::{pkey::{Id, PKey, Private}, sha::sha256};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+
+/// The device ID an account's Ed25519 signing key is logged under. Real device IDs are UUIDs, so it can't clash with one.
+pub const SIGNING_KEY: &str = "signing";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogEntry
+{
+    pub index: i64,
+    pub account_id: String,
+    /// The device the key belongs to, empty for the account's identity key, or `SIGNING_KEY` for its signing key.
+    pub device_id: String,
+    /// The published key. Empty if the key was revoked.
+    pub public_key: Vec<u8>,
+    pub time: String
+}
+
+impl LogEntry
+{
+    fn from_document(doc: &Document) -> LogEntry
+    {
+        LogEntry {
+            index: doc.get_i64("index").unwrap(),
+            account_id: doc.get_str("account_id").unwrap().to_string(),
+            device_id: doc.get_str("device_id").unwrap().to_string(),
+            public_key: doc
+                .get_array("public_key")
+                .unwrap()
+                .iter()
+                .map(|x| x.as_i32().unwrap() as u8)
+                .collect(),
+            time: doc.get_str("time").unwrap().to_string()
+        }
+    }
+
+    /// The Merkle leaf hash of the entry. Always recomputed from the entry itself, never read from the database.
+    fn leaf_hash(&self) -> [u8; 32] { leaf_hash(&serde_json::to_vec(self).unwrap()) }
+}
+
+/// The size and root hash of the first `size` entries of the log, signed with the log key.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+struct TreeHead
+{
+    size: usize,
+    root: String,
+    /// Hex-encoded Ed25519 signature over `signed_data()`.
+    #[serde(default)]
+    signature: String
+}
+
+impl TreeHead
+{
+    fn from_document(doc: &Document) -> TreeHead
+    {
+        TreeHead {
+            size: doc.get_i64("size").unwrap() as usize,
+            root: doc.get_str("root").unwrap().to_string(),
+            signature: doc.get_str("signature").unwrap_or_default().to_string()
+        }
+    }
+
+    fn signed_data(&self) -> Vec<u8> { format!("crim key log\n{}\n{}", self.size, self.root).into_bytes() }
+
+    /// Signs the tree made of `leaves` with the log key.
+    fn sign(leaves: &[[u8; 32]]) -> Result<TreeHead, String>
+    {
+        let mut head: TreeHead = TreeHead { size: leaves.len(), root: hex::encode(merkle_root(leaves)), signature: String::new() };
+        head.signature = hex::encode(crypto::sign_with(&log_key()?, &head.signed_data()));
+        Ok(head)
+    }
+
+    fn signature_valid(&self) -> Result<bool, String>
+    {
+        let public_key: Vec<u8> = log_key()?.public_key_to_pem().map_err(|e| e.to_string())?;
+        let signature: Vec<u8> = hex::decode(&self.signature).unwrap_or_default();
+        Ok(crypto::verify_signature(&public_key, &self.signed_data(), &signature))
+    }
+
+    fn root_hash(&self) -> [u8; 32]
+    {
+        hex::decode(&self.root)
+            .ok()
+            .and_then(|x| x.try_into().ok())
+            .unwrap_or_default()
+    }
+}
+
+/// The log as far as this client has read it, along with each entry's leaf hash, and the signed head it has accepted.
+struct CachedLog
+{
+    entries: Vec<LogEntry>,
+    leaves: Vec<[u8; 32]>,
+    head: Option<TreeHead>
+}
+
+static CACHED_LOG: Mutex<CachedLog> = Mutex::new(CachedLog { entries: Vec::new(), leaves: Vec::new(), head: None });
+
+/// The key tree heads are signed with: an Ed25519 key made from the 32 hex-encoded bytes in KEY_LOG_KEY.
+fn log_key() -> Result<PKey<Private>, String>
+{
+    let seed: String = dotenv::var("KEY_LOG_KEY").map_err(|_| "KEY_LOG_KEY isn't set in the .env file, so the key log can't be checked.")?;
+    let seed: Vec<u8> = hex::decode(seed.trim())
+        .ok()
+        .filter(|x| x.len() == 32)
+        .ok_or("KEY_LOG_KEY in the .env file has to be 64 hex characters.")?;
+    PKey::private_key_from_raw_bytes(&seed, Id::ED25519).map_err(|e| e.to_string())
+}
+
+fn leaf_hash(data: &[u8]) -> [u8; 32]
+{
+    let mut input: Vec<u8> = vec![0x00];
+    input.extend_from_slice(data);
+    sha256(&input)
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32]
+{
+    let mut data: Vec<u8> = vec![0x01];
+    data.extend_from_slice(left);
+    data.extend_from_slice(right);
+    sha256(&data)
+}
+
+/// The largest power of two smaller than n. n has to be at least 2.
+fn split_point(n: usize) -> usize
+{
+    let mut k: usize = 1;
+    while k * 2 < n
+    {
+        k *= 2;
+    }
+    k
+}
+
+/// The Merkle tree hash of a list of leaf hashes.
+fn merkle_root(leaves: &[[u8; 32]]) -> [u8; 32]
+{
+    match leaves.len()
+    {
+        0 => sha256(&[]),
+        1 => leaves[0],
+        n =>
+        {
+            let k: usize = split_point(n);
+            node_hash(&merkle_root(&leaves[..k]), &merkle_root(&leaves[k..]))
+        }
+    }
+}
+
+/// The audit path for leaf `index` of a tree: the hashes needed to get from that leaf to the root, from the bottom up (RFC 6962, 2.1.1).
+fn audit_path(index: usize, leaves: &[[u8; 32]]) -> Vec<[u8; 32]>
+{
+    if leaves.len() <= 1
+    {
+        return Vec::new();
+    }
+    let k: usize = split_point(leaves.len());
+    let (mut path, sibling) = match index < k
+    {
+        true => (audit_path(index, &leaves[..k]), merkle_root(&leaves[k..])),
+        false => (audit_path(index - k, &leaves[k..]), merkle_root(&leaves[..k]))
+    };
+    path.push(sibling);
+    path
+}
+
+/// Checks an audit path from `leaf`, at `index` in a tree of `size` leaves, up to `root` (RFC 9162, 2.1.3.2).
+fn verify_audit_path(index: usize, size: usize, leaf: &[u8; 32], path: &[[u8; 32]], root: &[u8; 32]) -> bool
+{
+    if index >= size
+    {
+        return false;
+    }
+    let (mut f, mut s): (usize, usize) = (index, size - 1);
+    let mut r: [u8; 32] = *leaf;
+    for p in path
+    {
+        if s == 0
+        {
+            return false;
+        }
+        if f & 1 == 1 || f == s
+        {
+            r = node_hash(p, &r);
+            while f & 1 == 0 && f != 0
+            {
+                f >>= 1;
+                s >>= 1;
+            }
+        }
+        else
+        {
+            r = node_hash(&r, p);
+        }
+        f >>= 1;
+        s >>= 1;
+    }
+    s == 0 && r == *root
+}
+
+/// The consistency proof between the first `old_size` leaves of a tree and the whole of it (RFC 6962, 2.1.2).
+fn consistency_proof(old_size: usize, leaves: &[[u8; 32]]) -> Vec<[u8; 32]>
+{
+    fn subproof(m: usize, leaves: &[[u8; 32]], whole: bool) -> Vec<[u8; 32]>
+    {
+        let n: usize = leaves.len();
+        if m == n
+        {
+            return if whole { Vec::new() } else { vec![merkle_root(leaves)] };
+        }
+        let k: usize = split_point(n);
+        let (mut proof, other) = match m <= k
+        {
+            true => (subproof(m, &leaves[..k], whole), merkle_root(&leaves[k..])),
+            false => (subproof(m - k, &leaves[k..], false), merkle_root(&leaves[..k]))
+        };
+        proof.push(other);
+        proof
+    }
+    match old_size
+    {
+        0 => Vec::new(),
+        m => subproof(m, leaves, true)
+    }
+}
+
+/// Checks that a tree of `new_size` leaves with root `new_root` is the tree of `old_size` leaves with root `old_root` with more appended (RFC 9162, 2.1.4.2).
+fn verify_consistency(old_size: usize, new_size: usize, old_root: &[u8; 32], new_root: &[u8; 32], proof: &[[u8; 32]]) -> bool
+{
+    if old_size > new_size
+    {
+        return false;
+    }
+    // anything is an extension of the empty tree
+    if old_size == 0
+    {
+        return proof.is_empty();
+    }
+    if old_size == new_size
+    {
+        return proof.is_empty() && old_root == new_root;
+    }
+    let mut proof: Vec<[u8; 32]> = proof.to_vec();
+    if old_size.is_power_of_two()
+    {
+        proof.insert(0, *old_root);
+    }
+    if proof.is_empty()
+    {
+        return false;
+    }
+    let (mut f, mut s): (usize, usize) = (old_size - 1, new_size - 1);
+    while f & 1 == 1
+    {
+        f >>= 1;
+        s >>= 1;
+    }
+    let (mut fr, mut sr): ([u8; 32], [u8; 32]) = (proof[0], proof[0]);
+    for c in &proof[1..]
+    {
+        if s == 0
+        {
+            return false;
+        }
+        if f & 1 == 1 || f == s
+        {
+            fr = node_hash(c, &fr);
+            sr = node_hash(c, &sr);
+            while f & 1 == 0 && f != 0
+            {
+                f >>= 1;
+                s >>= 1;
+            }
+        }
+        else
+        {
+            sr = node_hash(&sr, c);
+        }
+        f >>= 1;
+        s >>= 1;
+    }
+    fr == *old_root && sr == *new_root && s == 0
+}
+
+/// Sets up the unique indexes that keep two clients from taking the same log index, or signing two heads of the same size. Run on startup.
+pub fn create_indexes() -> Result<(), Error>
+{
+    mongo::get_collection("key_log").create_index(
+        IndexModel::builder()
+            .keys(doc! {"index": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        None
+    )?;
+    mongo::get_collection("key_log_heads").create_index(
+        IndexModel::builder()
+            .keys(doc! {"size": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        None
+    )?;
+    Ok(())
+}
+
+/// True if a write failed because something with the same index or size is already there.
+fn is_duplicate(e: &Error) -> bool { matches!(&*e.kind, ErrorKind::Write(WriteFailure::WriteError(x)) if x.code == 11000) }
+
+/// Appends a key to the log. `device_id` is empty for identity keys, and `public_key` is empty when a key is revoked.
+/// It's covered by a signed tree head the next time any client reads the log.
+pub fn append(account_id: &str, device_id: &str, public_key: &[u8]) -> Result<LogEntry, Error>
+{
+    let log = mongo::get_collection("key_log");
+    loop
+    {
+        let last: Option<Document> = log.find_one(None, FindOneOptions::builder().sort(doc! {"index": -1}).build())?;
+        let entry: LogEntry = LogEntry {
+            index: last.map(|x| x.get_i64("index").unwrap() + 1).unwrap_or(0),
+            account_id: account_id.to_string(),
+            device_id: device_id.to_string(),
+            public_key: public_key.to_vec(),
+            time: chrono::offset::Local::now().to_string()
+        };
+        // the unique index is what keeps two clients appending at once from both taking the same slot
+        match log.insert_one(bson::to_document(&entry).unwrap(), None)
+        {
+            Ok(_) => return Ok(entry),
+            Err(e) if is_duplicate(&e) => continue, // someone else got this index first
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+/// Returns true if an account has anything in the log at all.
+pub fn has_entries(account_id: &str) -> bool
+{
+    mongo::get_collection("key_log")
+        .find_one(doc! {"account_id": account_id}, None)
+        .unwrap()
+        .is_some()
+}
+
+/// Reads the log in order, starting from entry `from`. Fails if any index is missing or repeated.
+fn entries_from(from: usize) -> Result<Vec<LogEntry>, String>
+{
+    let entries: Vec<LogEntry> = mongo::get_collection("key_log")
+        .find(doc! {"index": {"$gte": from as i64}}, FindOptions::builder().sort(doc! {"index": 1}).build())
+        .map_err(|e| e.to_string())?
+        .map(|x| x.map(|y| LogEntry::from_document(&y)).map_err(|e| e.to_string()))
+        .collect::<Result<Vec<LogEntry>, String>>()?;
+    match entries.iter().enumerate().find(|(i, x)| x.index != (from + i) as i64)
+    {
+        Some((i, _)) => Err(format!("The key log is missing entry {}.", from + i)),
+        None => Ok(entries)
+    }
+}
+
+/// The largest signed tree head in the database, if there is one. Fails if its signature doesn't check out.
+fn latest_head() -> Result<Option<TreeHead>, String>
+{
+    let head: Option<TreeHead> = mongo::get_collection("key_log_heads")
+        .find_one(None, FindOneOptions::builder().sort(doc! {"size": -1}).build())
+        .map_err(|e| e.to_string())?
+        .map(|x| TreeHead::from_document(&x));
+    match head
+    {
+        Some(head) if !head.signature_valid()? => Err("The key log's latest tree head isn't signed with the log key. Someone may be tampering with keys.".to_string()),
+        head => Ok(head)
+    }
+}
+
+fn tree_head_path() -> PathBuf { keystore::data_dir().join("key_log.json") }
+
+/// The tree head this client accepted last, from this run or an earlier one.
+fn pinned_head(log: &CachedLog) -> Option<TreeHead>
+{
+    log.head.clone().or_else(|| {
+        fs::read(tree_head_path())
+            .ok()
+            .and_then(|x| serde_json::from_slice(&x).ok())
+    })
+}
+
+/// Moves the pinned tree head on to `head`, if it's the root of the log's first `head.size` entries and a consistency proof shows it extends the pinned one.
+fn advance(log: &mut CachedLog, head: TreeHead) -> Result<(), String>
+{
+    if log.head.as_ref() == Some(&head)
+    {
+        return Ok(());
+    }
+    if head.size > log.leaves.len() || merkle_root(&log.leaves[..head.size]) != head.root_hash()
+    {
+        return Err("The key log doesn't match its signed tree head. Someone may be tampering with keys.".to_string());
+    }
+    if let Some(pinned) = pinned_head(log)
+    {
+        let proof: Vec<[u8; 32]> = match pinned.size <= head.size
+        {
+            true => consistency_proof(pinned.size, &log.leaves[..head.size]),
+            false => Vec::new()
+        };
+        if !verify_consistency(pinned.size, head.size, &pinned.root_hash(), &head.root_hash(), &proof)
+        {
+            return Err("The key log has been rewritten since this device last saw it. Someone may be tampering with keys.".to_string());
+        }
+    }
+    keystore::write_private_file(&tree_head_path(), &serde_json::to_vec(&head).unwrap()).map_err(|e| e.to_string())?;
+    log.head = Some(head);
+    Ok(())
+}
+
+/// Brings the cached log up to date with anything appended since it was last read, and moves the pinned head on to the latest signed one.
+/// If `sign` is set and there are entries no signed head covers yet, signs and publishes one that does.
+fn refresh(log: &mut CachedLog, sign: bool) -> Result<(), String>
+{
+    // the head is read before the entries, so every entry it covers has been written by the time they're read
+    let published: Option<TreeHead> = latest_head()?;
+    let new: Vec<LogEntry> = entries_from(log.entries.len())?;
+    log.leaves.extend(new.iter().map(|x| x.leaf_hash()));
+    log.entries.extend(new);
+    if let Some(published) = published
+    {
+        advance(log, published)?;
+    }
+    let signed: usize = log.head.as_ref().map(|x| x.size).unwrap_or(0);
+    if sign && signed < log.leaves.len()
+    {
+        let head: TreeHead = TreeHead::sign(&log.leaves)?;
+        match mongo::get_collection("key_log_heads").insert_one(bson::to_document(&head).map_err(|e| e.to_string())?, None)
+        {
+            Ok(_) => (),
+            Err(e) if is_duplicate(&e) => (), // someone else signed this size first; `advance()` still checks ours
+            Err(e) => return Err(e.to_string())
+        }
+        advance(log, head)?;
+    }
+    Ok(())
+}
+
+/// The cached log, brought up to date and covered by a signed head.
+fn cached_log() -> Result<MutexGuard<'static, CachedLog>, String>
+{
+    let mut log: MutexGuard<CachedLog> = CACHED_LOG.lock().unwrap();
+    refresh(&mut log, true)?;
+    Ok(log)
+}
+
+/// The latest entry for an account and device in the pinned tree, checked by its audit path to the pinned root.
+fn latest_entry<'a>(log: &'a CachedLog, account_id: &str, device_id: &str) -> Result<Option<&'a LogEntry>, String>
+{
+    let head: &TreeHead = match &log.head
+    {
+        Some(head) => head,
+        None => return Ok(None)
+    };
+    let entry: Option<&LogEntry> = log.entries[..head.size]
+        .iter()
+        .rev()
+        .find(|x| x.account_id == account_id && x.device_id == device_id);
+    if let Some(entry) = entry
+    {
+        let index: usize = entry.index as usize;
+        let path: Vec<[u8; 32]> = audit_path(index, &log.leaves[..head.size]);
+        if !verify_audit_path(index, head.size, &entry.leaf_hash(), &path, &head.root_hash())
+        {
+            return Err(format!("A key for {} isn't in the signed key log. Someone may be tampering with keys.", Account::display_name(account_id)));
+        }
+    }
+    Ok(entry)
+}
+
+/// Checks that each of the given keys (account ID, device ID, public key) is the latest one logged for that account and device.
+pub fn verify_keys(keys: &[(&str, &str, &[u8])]) -> Result<(), String>
+{
+    let log: MutexGuard<CachedLog> = cached_log()?;
+    for (account_id, device_id, public_key) in keys
+    {
+        match latest_entry(&log, account_id, device_id)?
+        {
+            Some(latest) if latest.public_key == *public_key && !public_key.is_empty() => (),
+            _ => return Err(format!("A key for {} doesn't match the key log. Someone may be tampering with keys.", Account::display_name(account_id)))
+        }
+    }
+    Ok(())
+}
+
+/// The latest key logged for an account and device, or none if nothing has been logged for it or it was revoked.
+pub fn latest_key(account_id: &str, device_id: &str) -> Result<Option<Vec<u8>>, String>
+{
+    let log: MutexGuard<CachedLog> = cached_log()?;
+    Ok(latest_entry(&log, account_id, device_id)?
+        .filter(|x| !x.public_key.is_empty())
+        .map(|x| x.public_key.clone()))
+}
+
+/// Checks every key an account would have conversation keys encrypted with: each approved device, or the identity key if there are none.
+pub fn verify_account(account: &Account) -> Result<(), String>
+{
+    let mut keys: Vec<(&str, &str, &[u8])> = account
+        .devices
+        .iter()
+        .filter(|x| x.approved)
+        .map(|x| (account.id.as_str(), x.id.as_str(), x.public_key.as_slice()))
+        .collect();
+    if keys.is_empty()
+    {
+        keys.push((account.id.as_str(), "", account.public_key.as_slice()));
+    }
+    verify_keys(&keys)
+}
+
+/// `crim audit-keys`: checks the whole log hangs together, prints it, and points out any account whose current keys aren't the ones in the log.
+/// Entries that no signed head covers yet are listed, but not signed. Returns false if anything is wrong.
+pub fn audit() -> bool
+{
+    let mut log: MutexGuard<CachedLog> = CACHED_LOG.lock().unwrap();
+    let mut ok: bool = true;
+    if let Err(e) = refresh(&mut log, false)
+    {
+        println!("{}", e);
+        ok = false;
+    }
+    let signed: usize = log.head.as_ref().map(|x| x.size).unwrap_or(0);
+    for entry in &log.entries
+    {
+        let key: String = match entry.public_key.is_empty()
+        {
+            true => "revoked".to_string(),
+            false => hex::encode(sha256(&entry.public_key))[..16].to_string()
+        };
+        let device: &str = if entry.device_id.is_empty() { "identity" } else { entry.device_id.as_str() };
+        let unsigned: &str = if entry.index as usize >= signed { " | not signed yet" } else { "" };
+        println!("{:>6} | {} | {} | {} | {}{}", entry.index, entry.time, entry.account_id, device, key, unsigned);
+    }
+    for account in mongo::get_collection("accounts").find(None, None).unwrap()
+    {
+        let account: Account = Account::from_document(account.unwrap());
+        // the signing key is always checked, so an account that's had its signing key removed shows up too
+        let mut keys: Vec<(&str, &[u8])> = vec![("", account.public_key.as_slice()), (SIGNING_KEY, account.signing_key.as_slice())];
+        keys.extend(account.devices.iter().map(|x| (x.id.as_str(), x.public_key.as_slice())));
+        for (device_id, public_key) in keys
+        {
+            let latest: Option<&LogEntry> = log
+                .entries
+                .iter()
+                .rev()
+                .find(|x| x.account_id == account.id && x.device_id == device_id);
+            if latest.map(|x| x.public_key.as_slice()).unwrap_or_default() != public_key
+            {
+                let device: &str = match device_id
+                {
+                    "" => "identity key",
+                    SIGNING_KEY => "signing key",
+                    _ => device_id
+                };
+                println!("MISMATCH: {} ({}) {} isn't the latest key in the log.", account.username, account.id, device);
+                ok = false;
+            }
+        }
+    }
+    match &log.head
+    {
+        Some(head) => println!("{} entries, signed tree head of {} with root {}", log.entries.len(), head.size, head.root),
+        None => println!("{} entries, no signed tree head", log.entries.len())
+    }
+    ok
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /// The leaf inputs from the RFC 6962 test vectors used by Certificate Transparency implementations.
+    const LEAVES: [&str; 8] = ["", "00", "10", "2021", "3031", "40414243", "5051525354555657", "606162636465666768696a6b6c6d6e6f"];
+
+    /// The expected roots of the first 1 to 8 of those leaves.
+    const ROOTS: [&str; 8] = [
+        "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d",
+        "fac54203e7cc696cf0dfcb42c92a1d9dbaf70ad9e621f4bd8d98662f00e3c125",
+        "aeb6bcfe274b70a14fb067a5e5578264db0fa9b51af5e0ba159158f329e06e77",
+        "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+        "4e3bbb1f7b478dcfe71fb631631519a3bca12c9aefca1612bfce4c13a86264d4",
+        "76e67dadbcdf1e10e1b74ddc608abd2f98dfb16fbce75277b5232a127f2087ef",
+        "ddb89be403809e325750d3d263cd78929c2942b7942a34b77e122c9594a74c8c",
+        "5dc9da79a70659a9ad559cb701ded9a2ab9d823aad2f4960cfe370eff4604328"
+    ];
+
+    fn leaves() -> Vec<[u8; 32]> { LEAVES.iter().map(|x| leaf_hash(&hex::decode(x).unwrap())).collect() }
+
+    #[test]
+    fn empty_tree_is_the_hash_of_nothing()
+    {
+        assert_eq!(hex::encode(merkle_root(&[])), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    }
+
+    #[test]
+    fn roots_match_rfc_6962_vectors()
+    {
+        let leaves: Vec<[u8; 32]> = leaves();
+        for (size, root) in ROOTS.iter().enumerate()
+        {
+            assert_eq!(hex::encode(merkle_root(&leaves[..size + 1])), *root, "tree of {} leaves", size + 1);
+        }
+    }
+
+    #[test]
+    fn audit_paths_lead_to_the_root()
+    {
+        let leaves: Vec<[u8; 32]> = leaves();
+        for size in 1..=leaves.len()
+        {
+            let root: [u8; 32] = merkle_root(&leaves[..size]);
+            for index in 0..size
+            {
+                let path: Vec<[u8; 32]> = audit_path(index, &leaves[..size]);
+                assert!(verify_audit_path(index, size, &leaves[index], &path, &root), "leaf {} of {}", index, size);
+            }
+        }
+    }
+
+    #[test]
+    fn audit_paths_fail_for_the_wrong_leaf_or_position()
+    {
+        let leaves: Vec<[u8; 32]> = leaves();
+        let root: [u8; 32] = merkle_root(&leaves);
+        let path: Vec<[u8; 32]> = audit_path(5, &leaves);
+        assert_eq!(path.len(), 3);
+        assert!(!verify_audit_path(5, 8, &leaf_hash(b"tampered"), &path, &root));
+        assert!(!verify_audit_path(4, 8, &leaves[5], &path, &root));
+        assert!(!verify_audit_path(8, 8, &leaves[5], &path, &root));
+        assert!(!verify_audit_path(5, 8, &leaves[5], &path[..2], &root));
+    }
+
+    #[test]
+    fn consistency_proofs_verify_between_every_pair_of_sizes()
+    {
+        let leaves: Vec<[u8; 32]> = leaves();
+        for new_size in 1..=leaves.len()
+        {
+            let new_root: [u8; 32] = merkle_root(&leaves[..new_size]);
+            for old_size in 0..=new_size
+            {
+                let proof: Vec<[u8; 32]> = consistency_proof(old_size, &leaves[..new_size]);
+                let old_root: [u8; 32] = merkle_root(&leaves[..old_size]);
+                assert!(verify_consistency(old_size, new_size, &old_root, &new_root, &proof), "{} to {}", old_size, new_size);
+            }
+        }
+    }
+
+    #[test]
+    fn consistency_proofs_fail_for_a_rewritten_log()
+    {
+        let leaves: Vec<[u8; 32]> = leaves();
+        let mut rewritten: Vec<[u8; 32]> = leaves.clone();
+        rewritten[1] = leaf_hash(b"tampered");
+        let old_root: [u8; 32] = merkle_root(&leaves[..3]);
+        let proof: Vec<[u8; 32]> = consistency_proof(3, &rewritten);
+        assert!(!verify_consistency(3, 8, &old_root, &merkle_root(&rewritten), &proof));
+        // shrinking is never consistent, and neither is a different tree of the same size
+        assert!(!verify_consistency(8, 3, &merkle_root(&leaves), &old_root, &[]));
+        assert!(!verify_consistency(8, 8, &merkle_root(&leaves), &merkle_root(&rewritten), &[]));
+    }
+
+    #[test]
+    fn changing_any_leaf_changes_the_root()
+    {
+        let leaves: Vec<[u8; 32]> = leaves();
+        for i in 0..leaves.len()
+        {
+            let mut changed: Vec<[u8; 32]> = leaves.clone();
+            changed[i] = leaf_hash(b"tampered");
+            assert_ne!(merkle_root(&changed), merkle_root(&leaves));
+        }
+    }
+}
@@ -0,0 +1,199 @@
+/*
+
+Local storage for private keys.
+
+Keys are kept in a per-user data directory, one file per account, so several people can use CRIM on the same machine without overwriting each other's keys.
+The directory is, in order of preference:
+CRIM_DATA_DIR in the .env file
+$XDG_DATA_HOME/crim, or ~/.local/share/crim (Linux and other Unix)
+~/Library/Application Support/crim (macOS)
+%APPDATA%\crim (Windows)
+
+Directories are created readable only by the current user (0700), and key files likewise (0600).
+
+Key files hold this device's private key (see Device in structs.rs), encrypted with a passphrase derived from the account's identity key.
+The identity key is only ever available by decrypting `priv_key_enc` with the account password, so the key file is useless without the password,
+but it keeps working after the password is changed on another device.
+While an account's keys are being upgraded, the new device key is saved next to the old one as a staged key, and only moved over it once the
+account has switched; reading a device key falls back to the staged one, so a device can't lose its key to an upgrade that stops halfway.
+Once the user logs in, the decrypted device key is kept in memory for the rest of the session, and zeroed when they log out (or when it's dropped),
+so nothing usable is left on disk if CRIM crashes.
+
+*/
+
+use openssl::{pkey::{PKey, Private}, sha::sha256, symm::Cipher};
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+
+/// Resolves the directory CRIM keeps its local data in. Panics if there's no way to tell where the user's home directory is.
+pub fn data_dir() -> PathBuf
+{
+    if let Ok(dir) = dotenv::var("CRIM_DATA_DIR")
+    {
+        return PathBuf::from(dir);
+    }
+    platform_data_dir().expect("Could not find a data directory for CRIM. Set CRIM_DATA_DIR in the .env file.")
+}
+
+#[cfg(target_os = "windows")]
+fn platform_data_dir() -> Option<PathBuf> { std::env::var_os("APPDATA").map(|x| PathBuf::from(x).join("crim")) }
+
+#[cfg(target_os = "macos")]
+fn platform_data_dir() -> Option<PathBuf> { std::env::var_os("HOME").map(|x| PathBuf::from(x).join("Library/Application Support/crim")) }
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn platform_data_dir() -> Option<PathBuf>
+{
+    match std::env::var_os("XDG_DATA_HOME").filter(|x| !x.is_empty())
+    {
+        Some(dir) => Some(PathBuf::from(dir).join("crim")),
+        None => std::env::var_os("HOME").map(|x| PathBuf::from(x).join(".local/share/crim"))
+    }
+}
+
+/// Creates a directory (and any missing parents) that only the current user can access.
+pub fn create_private_dir(dir: &PathBuf) -> io::Result<()>
+{
+    let mut builder: DirBuilder = DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    builder.mode(0o700);
+    builder.create(dir)?;
+    // the mode above only applies to directories that didn't exist yet
+    #[cfg(unix)]
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    Ok(())
+}
+
+/// Writes a file that only the current user can read, creating its directory if needed.
+pub fn write_private_file(path: &PathBuf, contents: &[u8]) -> io::Result<()>
+{
+    if let Some(parent) = path.parent()
+    {
+        create_private_dir(&parent.to_path_buf())?;
+    }
+    let mut options: OpenOptions = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file: fs::File = options.open(path)?;
+    file.write_all(contents)
+}
+
+/// The unlocked device key for the current session.
+static UNLOCKED_KEY: Mutex<Option<UnlockedKey>> = Mutex::new(None);
+
+struct UnlockedKey
+{
+    account_id: String,
+    device_id: String,
+    /// The private key as PEM.
+    pem: Zeroizing<Vec<u8>>
+}
+
+/// The path of the key file for a given account ID.
+pub fn key_path(account_id: &str) -> PathBuf { data_dir().join("keys").join(format!("{}.key", account_id)) }
+
+/// The path of the staged key file for a given account ID (see `stage_device_key()`).
+fn staged_key_path(account_id: &str) -> PathBuf { data_dir().join("keys").join(format!("{}.key.new", account_id)) }
+
+/// The passphrase device keys are encrypted with: a hash of the account's identity private key.
+fn device_passphrase(identity: &PKey<Private>) -> Zeroizing<Vec<u8>>
+{
+    let der: Zeroizing<Vec<u8>> = Zeroizing::new(identity.private_key_to_der().expect("failed to read identity key"));
+    Zeroizing::new(hex::encode(sha256(&der)).into_bytes())
+}
+
+/// Writes a device key to a file, encrypted with a passphrase derived from the account's identity key.
+fn write_key_file(path: &PathBuf, device_key: &PKey<Private>, identity: &PKey<Private>) -> io::Result<()>
+{
+    let pem: Vec<u8> = device_key
+        .private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), &device_passphrase(identity))
+        .map_err(io::Error::other)?;
+    write_private_file(path, &pem)
+}
+
+/// Reads a device key from a file. Returns none if there's no file, or it can't be opened with the given identity key.
+fn read_key_file(path: &PathBuf, identity: &PKey<Private>) -> Option<PKey<Private>>
+{
+    let pem: Vec<u8> = fs::read(path).ok()?;
+    PKey::private_key_from_pem_passphrase(&pem, &device_passphrase(identity)).ok()
+}
+
+/// Saves this device's private key for an account, encrypted with a passphrase derived from the account's identity key.
+pub fn write_device_key(account_id: &str, device_key: &PKey<Private>, identity: &PKey<Private>) -> io::Result<()>
+{
+    write_key_file(&key_path(account_id), device_key, identity)
+}
+
+/// Reads this device's private key for an account, or the staged one if that's the one the identity key opens.
+/// Returns none if this device has no key for the account that can be opened with the given identity key.
+pub fn read_device_key(account_id: &str, identity: &PKey<Private>) -> Option<PKey<Private>>
+{
+    read_key_file(&key_path(account_id), identity).or_else(|| read_key_file(&staged_key_path(account_id), identity))
+}
+
+/// Saves a new device key for an account without replacing the current one. Used while upgrading keys.
+pub fn stage_device_key(account_id: &str, device_key: &PKey<Private>, identity: &PKey<Private>) -> io::Result<()>
+{
+    write_key_file(&staged_key_path(account_id), device_key, identity)
+}
+
+/// Replaces an account's device key with the staged one.
+pub fn commit_staged_key(account_id: &str) -> io::Result<()> { fs::rename(staged_key_path(account_id), key_path(account_id)) }
+
+/// Throws away an account's staged device key. Does nothing if there isn't one.
+pub fn discard_staged_key(account_id: &str) -> io::Result<()>
+{
+    match fs::remove_file(staged_key_path(account_id))
+    {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(())
+    }
+}
+
+/// Removes the private key for an account. Does nothing if there isn't one.
+pub fn clear_private_key(account_id: &str) -> io::Result<()>
+{
+    match fs::remove_file(key_path(account_id))
+    {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(())
+    }
+}
+
+/// Holds this device's decrypted private key in memory for the rest of the session. Replaces, and zeroes, any key that was already unlocked.
+pub fn unlock(account_id: &str, device_id: &str, device_key: &PKey<Private>)
+{
+    let pem: Zeroizing<Vec<u8>> = Zeroizing::new(device_key.private_key_to_pem_pkcs8().expect("failed to export device key"));
+    *UNLOCKED_KEY.lock().unwrap() = Some(UnlockedKey { account_id: account_id.to_string(), device_id: device_id.to_string(), pem });
+}
+
+/// Zeroes and forgets the unlocked private key. Called on logout.
+pub fn lock() { *UNLOCKED_KEY.lock().unwrap() = None; }
+
+/// Returns the unlocked device key for an account, or none if that account's key isn't unlocked.
+pub fn private_key(account_id: &str) -> Option<PKey<Private>>
+{
+    match UNLOCKED_KEY.lock().unwrap().as_ref()
+    {
+        Some(key) if key.account_id == account_id => PKey::private_key_from_pem(&key.pem).ok(),
+        _ => None
+    }
+}
+
+/// Returns the ID of the device whose key is unlocked for an account, or none if that account's key isn't unlocked.
+pub fn device_id(account_id: &str) -> Option<String>
+{
+    match UNLOCKED_KEY.lock().unwrap().as_ref()
+    {
+        Some(key) if key.account_id == account_id => Some(key.device_id.clone()),
+        _ => None
+    }
+}
@@ -1,24 +1,281 @@
 extern crate dotenv;
-use crate::messenger::messenger_panel;
+use crate::messenger::{message_relay, messenger_panel};
 use super::utils;
-use super::structs::Account;
+use super::account_cache::{self, CachedAccount};
+use super::crypto;
+use super::key_log;
+use super::keystore;
+use super::session;
+use super::totp;
+use super::password_policy::PasswordPolicy;
+use super::structs::{Account, Device};
 use argon2::Argon2;
 use base64::{engine::general_purpose, Engine as _};
 use getrandom::getrandom;
-use openssl::{pkey::PKey, rsa::Rsa, symm::Cipher};
-use std::fs::File;
-use std::io::Write;
+use openssl::{pkey::{Id, PKey, Private}, symm::Cipher};
 
 /*
 
 This file handles the login system of CRIM.
-Accounts.json is a local cache of accounts, to allow for quick sign in.
+Accounts.json is a local cache of accounts, to allow for quick sign in (see account_cache.rs).
 Any account that is being logged in with will be checked against the account database in the server so as to prevent fake accounts; registering is necessary.
 */
 
 fn validate_login_info(account_to_be_validated: &Account) -> Option<Account>
 {
-    Account::get_account(&account_to_be_validated.username)
+    Account::get_account_by_id(&account_to_be_validated.id)
+}
+
+/// Hashes a password with the given salt using Argon2, and returns it base64-encoded. This is the format stored in `Account.hash`.
+fn hash_password(password: &[u8], salt: &[u8]) -> String
+{
+    let mut output: [u8; 256] = [0u8; 256];
+    Argon2::default()
+        .hash_password_into(password, salt, &mut output)
+        .expect("failed to hash password");
+    general_purpose::STANDARD.encode(output)
+}
+
+/// Checks a password against an account's stored hash.
+pub fn verify_password(account: &Account, password: &str) -> bool { hash_password(password.as_bytes(), &account.salt) == account.hash }
+
+/// Checks a two-factor code for an account. Accepts either a current TOTP code or one of the account's backup codes.
+/// Either is used up once it's accepted, so the same code can't get in twice. Accounts without two-factor authentication turned on always pass.
+pub fn verify_second_factor(account: &Account, code: &str) -> bool
+{
+    let secret: &String = match &account.totp_secret
+    {
+        Some(secret) => secret,
+        None => return true
+    };
+    if let Some(step) = totp::verify(secret, code)
+    {
+        return Account::use_totp_step(&account.id, step as i64).unwrap_or(false);
+    }
+    match account.backup_codes.iter().find(|x| totp::backup_code_matches(x, code))
+    {
+        Some(stored) => Account::use_backup_code(&account.id, stored).unwrap_or(false),
+        None => false
+    }
+}
+
+/// Changes an account's password.
+///
+/// The old password is checked against the stored hash, then used to decrypt `priv_key_enc`, which is re-encrypted with the new password.
+pub fn change_password(user: &Account, old_password: &str, new_password: &str) -> Result<(), String>
+{
+    let account: Account = Account::get_account_by_id(&user.id).ok_or("Your account could not be found.")?;
+    if !verify_password(&account, old_password)
+    {
+        return Err("Current password is incorrect.".to_string());
+    }
+    let pkey: PKey<Private> = PKey::private_key_from_pem_passphrase(&account.priv_key_enc, old_password.as_bytes())
+        .map_err(|_| "Failed to unlock your private key with the current password.".to_string())?;
+    rewrap_credentials(&account, &pkey, new_password)
+}
+
+/// Moves an RSA account over to X25519 and Ed25519 keys (see crypto.rs).
+///
+/// The account gets a new identity key, and this device gets a new device key that every conversation key is re-wrapped for.
+/// The account's other devices are signed out and removed, since their keys can't be replaced from here; they get set up again the next time they log in.
+/// The old recovery key only opens the old identity key, so if the account had one, a new one is made and returned.
+///
+/// The account itself switches over in one conditional write. Everything before that (the staged device key, the key log, the new conversation keys
+/// next to the old ones) is undone if it doesn't go through; everything after is tidying up that can't leave the account unreadable.
+pub fn upgrade_keys(user: &Account, password: &str) -> Result<Option<String>, String>
+{
+    let account: Account = Account::get_account_by_id(&user.id).ok_or("Your account could not be found.")?;
+    // a logged signing key means the account has been upgraded before, even if its algorithm has since been set back to RSA
+    if account.algorithm != crypto::ALGORITHM_RSA || key_log::latest_key(&account.id, key_log::SIGNING_KEY)?.is_some()
+    {
+        return Err("Your account already uses the newest keys.".to_string());
+    }
+    if !verify_password(&account, password)
+    {
+        return Err("Incorrect password. Your keys were not changed.".to_string());
+    }
+    let old_identity: PKey<Private> = PKey::private_key_from_pem_passphrase(&account.priv_key_enc, password.as_bytes())
+        .map_err(|_| "Failed to unlock your private key.".to_string())?;
+    let old_device_key: PKey<Private> = keystore::private_key(&account.id).ok_or("Your private key is not unlocked.")?;
+
+    let identity: PKey<Private> = crypto::generate_key();
+    let (device, device_key) = new_device(&identity, true);
+    // work out every conversation's new keys before changing anything, so if this fails the account is left as it was
+    let conversation_keys = message_relay::upgrade_conversation_keys(&account.id, &old_identity, &old_device_key, &device)?;
+
+    let cipher: Cipher = Cipher::aes_256_cbc();
+    let recovery_key: Option<String> = match account.priv_key_recovery.is_empty()
+    {
+        true => None,
+        false => Some(generate_recovery_key())
+    };
+    let mut upgraded: Account = account.clone();
+    upgraded.algorithm = crypto::ALGORITHM_CURVE25519.to_string();
+    upgraded.public_key = identity.public_key_to_pem().unwrap();
+    upgraded.priv_key_enc = identity.private_key_to_pem_pkcs8_passphrase(cipher, password.as_bytes()).unwrap();
+    upgraded.signing_key = crypto::signing_key(&identity).public_key_to_pem().unwrap();
+    upgraded.priv_key_recovery = match &recovery_key
+    {
+        Some(recovery_key) => identity
+            .private_key_to_pem_pkcs8_passphrase(cipher, normalize_recovery_key(recovery_key).as_bytes())
+            .unwrap(),
+        None => Vec::new()
+    };
+    upgraded.devices = vec![device.clone()];
+
+    // save this device's new key next to its old one, so it can open whichever keys the account ends up with
+    keystore::stage_device_key(&account.id, &device_key, &identity).map_err(|e| format!("This device's new key could not be saved, so your keys were not changed: {}", e))?;
+    // undoes everything below that's happened so far, if the account doesn't end up switching over
+    let roll_back = |error: String| -> String {
+        let undo = || -> Result<(), String> {
+            message_relay::remove_conversation_keys(&conversation_keys, false)?;
+            let republish = || -> Result<(), mongodb::error::Error> {
+                key_log::append(&account.id, "", &account.public_key)?;
+                key_log::append(&account.id, key_log::SIGNING_KEY, &[])?;
+                key_log::append(&account.id, &device.id, &[])?;
+                for old in &account.devices
+                {
+                    key_log::append(&account.id, &old.id, &old.public_key)?;
+                }
+                Ok(())
+            };
+            republish().map_err(|e| e.to_string())?;
+            keystore::discard_staged_key(&account.id).map_err(|e| e.to_string())
+        };
+        match undo()
+        {
+            Ok(()) => format!("{} Your keys were not changed.", error),
+            Err(e) => format!("{} Your keys were not changed, but undoing the upgrade failed partway ({}), so some people may not be able to message you until you try again.", error, e)
+        }
+    };
+
+    // publish the new keys (and revoke the old devices) before anyone can be handed them
+    let publish = || -> Result<(), mongodb::error::Error> {
+        key_log::append(&account.id, "", &upgraded.public_key)?;
+        key_log::append(&account.id, key_log::SIGNING_KEY, &upgraded.signing_key)?;
+        key_log::append(&account.id, &device.id, &device.public_key)?;
+        for old in &account.devices
+        {
+            key_log::append(&account.id, &old.id, &[])?;
+        }
+        Ok(())
+    };
+    if let Err(e) = publish()
+    {
+        return Err(roll_back(format!("An error occurred publishing your new keys: {}.", e)));
+    }
+    // the new conversation keys go in next to the old ones, so nothing can be read by neither set of keys at any point
+    if let Err(e) = message_relay::add_conversation_keys(&conversation_keys)
+    {
+        return Err(roll_back(format!("An error occurred re-encrypting your conversations: {}.", e)));
+    }
+    match Account::replace_keys(&account, &upgraded)
+    {
+        Ok(true) => (),
+        Ok(false) => return Err(roll_back("Your account was changed while its keys were being upgraded.".to_string())),
+        Err(e) => return Err(roll_back(format!("An error occurred switching your account over: {}.", e)))
+    }
+
+    // the account has switched, so from here on there's nothing to roll back; anything left undone is tidied up the next time this device logs in
+    let upgraded: Account = Account::get_account_by_id(&account.id).ok_or("Your account could not be found.")?;
+    if let Err(e) = keystore::commit_staged_key(&account.id)
+    {
+        utils::addl_message(&format!("This device's new key could not replace its old one, but it's still saved: {}", e), "yellow");
+    }
+    if let Err(e) = message_relay::remove_conversation_keys(&conversation_keys, true)
+    {
+        utils::addl_message(&format!("Your old conversation keys could not all be removed: {}", e), "yellow");
+    }
+    keystore::unlock(&account.id, &device.id, &device_key);
+    account_cache::remember(&upgraded);
+    for other in session::list(&account.id).iter().filter(|x| !x.is_current())
+    {
+        let _ = session::revoke(&account.id, &other.id);
+    }
+    Ok(recovery_key)
+}
+
+/// Decrypts the recovery copy of an account's private key with a recovery key. Returns none if the account has no recovery key or the key is wrong.
+fn open_recovery_copy(account: &Account, recovery_key: &str) -> Option<PKey<Private>>
+{
+    if account.priv_key_recovery.is_empty()
+    {
+        return None;
+    }
+    PKey::private_key_from_pem_passphrase(&account.priv_key_recovery, normalize_recovery_key(recovery_key).as_bytes()).ok()
+}
+
+/// Re-encrypts an account's private key with a new password, and stores it along with a new salt and hash.
+///
+/// Everything is written in a single update that only applies if the stored hash hasn't changed. The stored copy is then read back and checked;
+/// if it can't be opened with the new password, the old credentials are put back.
+fn rewrap_credentials(account: &Account, pkey: &PKey<Private>, new_password: &str) -> Result<(), String>
+{
+    PasswordPolicy::from_env()
+        .check(new_password, &account.username)
+        .map_err(|x| x.join("\n"))?;
+
+    let mut salt: [u8; 256] = [0; 256];
+    getrandom(&mut salt).expect("Failed to generate random salt.");
+    let hash: String = hash_password(new_password.as_bytes(), &salt);
+    let priv_key_enc: Vec<u8> = pkey
+        .private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), new_password.as_bytes())
+        .map_err(|_| "Failed to re-encrypt your private key.".to_string())?;
+    // make sure the new copy actually opens before anything touches the database
+    if PKey::private_key_from_pem_passphrase(&priv_key_enc, new_password.as_bytes()).is_err()
+    {
+        return Err("Failed to re-encrypt your private key.".to_string());
+    }
+
+    match Account::swap_credentials(&account.id, &account.hash, &hash, &salt, &priv_key_enc)
+    {
+        Ok(true) => {}
+        Ok(false) => return Err("Your account was changed while updating. Nothing was modified; please try again.".to_string()),
+        Err(e) => return Err(format!("An error occurred updating your account: {}", e))
+    }
+
+    let stored_ok: bool = match Account::get_account_by_id(&account.id)
+    {
+        Some(stored) => stored.hash == hash && PKey::private_key_from_pem_passphrase(&stored.priv_key_enc, new_password.as_bytes()).is_ok(),
+        None => false
+    };
+    if !stored_ok
+    {
+        // roll back to the old credentials. the filter is on the new hash so this can't clobber anything written since.
+        return match Account::swap_credentials(&account.id, &hash, &account.hash, &account.salt, &account.priv_key_enc)
+        {
+            Ok(true) => Err("Failed to verify the updated account, so your password was not changed.".to_string()),
+            Ok(false) => Err("Failed to verify the updated account, and it was changed again before the old password could be put back. \
+                Your password may or may not have changed; if neither works, use your recovery key.".to_string()),
+            Err(e) => Err(format!("Failed to verify the updated account, and the old password could not be put back ({}). \
+                Your new password may not unlock your keys; if you can't log in, use your recovery key.", e))
+        };
+    }
+    Ok(())
+}
+
+/// Generates a recovery key: 200 random bits, base32-encoded and split into groups of five so it's easier to write down.
+fn generate_recovery_key() -> String
+{
+    let mut bytes: [u8; 25] = [0; 25];
+    getrandom(&mut bytes).expect("Failed to generate recovery key.");
+    utils::base32_encode(&bytes)
+        .chars()
+        .collect::<Vec<char>>()
+        .chunks(5)
+        .map(|x| x.iter().collect::<String>())
+        .collect::<Vec<String>>()
+        .join("-")
+}
+
+/// Strips dashes, spaces and case out of a recovery key, so it works however it was written down.
+fn normalize_recovery_key(recovery_key: &str) -> String
+{
+    recovery_key
+        .chars()
+        .filter(|x| x.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_uppercase()
 }
 
 /*
@@ -28,54 +285,102 @@ fn validate_login_info(account_to_be_validated: &Account) -> Option<Account>
 ====================================================*/
 
 /// Registers a new account in the database
-/// Username uniquity is enforced, and usernames cannot currently be changed, because the friend system relies on usernames.
-/// 
-/// Could be refactored to use UUIDs instead of usernames to allow for username changing, but i still think uniquity makes things clearer for everyone.
-fn register_account(addl_message: Option<&str>)
+/// Username uniquity is enforced. Every account also gets a random UUID, which is what friends and conversations reference, so usernames can be changed later.
+/// Passwords have to pass the password policy (see password_policy.rs); the user is asked again until they do.
+fn register_account()
 {
-    if let Some(msg) = addl_message
-    {
-        // hard-code red because if this function succeeds everything is cleared anyway. only errors need to be shown
-        utils::addl_message(msg, "red");
-    }
-
     utils::clear();
 
-    let username: String = utils::grab_str_input(Some("Please input a username for your new account:"));
-    let unique_query: Option<Account> = Account::get_account(&username);
-    if unique_query.is_some()
+    let username: String = loop
     {
-        register_account(Some("Username already exists. Please try again."));
-    }
+        let username: String = utils::grab_str_input(Some("Please input a username for your new account:"));
+        if username.is_empty() || username.contains(char::is_whitespace)
+        {
+            utils::addl_message("Usernames can't be empty or contain spaces. Please try again.", "red");
+            continue;
+        }
+        if Account::get_account(&username).is_some()
+        {
+            utils::addl_message("Username already exists. Please try again.", "red");
+            continue;
+        }
+        break username;
+    };
 
-    let password: Vec<u8> = utils::grab_str_input(Some("Please input a password for your new account:")).into_bytes();
-    // turn this into bytes immediately so I don't have to clone it in the hash function
+    let policy: PasswordPolicy = PasswordPolicy::from_env();
+    let password: Vec<u8> = loop
+    {
+        let password: String = utils::grab_secret_input(Some("Please input a password for your new account:"));
+        if let Err(problems) = policy.check(&password, &username)
+        {
+            for problem in problems
+            {
+                utils::addl_message(&problem, "red");
+            }
+            continue;
+        }
+        if password != utils::grab_secret_input(Some("Please input your password again:"))
+        {
+            utils::addl_message("Passwords do not match. Please try again.", "red");
+            continue;
+        }
+        // turn this into bytes immediately so I don't have to clone it in the hash function
+        break password.into_bytes();
+    };
 
     // crypto login
 
     let mut salt: [u8; 256] = [0; 256];
     getrandom(&mut salt).expect("Failed to generate random salt.");
-    let mut output: [u8; 256] = [0u8; 256];
-    Argon2::default()
-        .hash_password_into(&password, &salt, &mut output)
-        .expect("failed to hash password");
-    let b64_pass: String = general_purpose::STANDARD.encode(output);
+    let b64_pass: String = hash_password(&password, &salt);
 
     // gen public and private keys
-    let pkey: PKey<openssl::pkey::Private> = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let id: String = uuid::Uuid::new_v4().to_string();
+    let pkey: PKey<Private> = crypto::generate_key();
     let cipher: Cipher = Cipher::aes_256_cbc();
     let public_key: Vec<u8> = pkey.public_key_to_pem().unwrap();
-    let private_key: Vec<u8> = pkey.private_key_to_pem_pkcs8().unwrap();
-    let mut file = File::create("src/userdata/pkey.key").unwrap(); // could be an env variable as to what pkey.key could be named
-    file.write_all(&private_key)
-        .expect("Error writing private key to pkey.key");
 
-    // actually encrypt priv key and save it
+    // actually encrypt priv key
     let private_key: Vec<u8> = pkey
         .private_key_to_pem_pkcs8_passphrase(cipher, &password)
         .unwrap();
     //https://docs.rs/openssl/latest/openssl/symm/index.html
-    let new_account: Account = Account { username, hash: b64_pass, salt: salt.to_vec(), public_key, priv_key_enc: private_key, friends: Vec::new() };
+
+    // this machine is the account's first device, so it's approved from the start
+    let (device, device_key) = new_device(&pkey, true);
+    keystore::write_device_key(&id, &device_key, &pkey).expect("Error saving device key.");
+    keystore::unlock(&id, &device.id, &device_key);
+
+    // a second copy of the private key, encrypted with a recovery key instead of the password, so a forgotten password doesn't lose everything.
+    let recovery_key: Option<String> = match utils::grab_str_input(Some("Would you like a recovery key? It's the only way back into your account if you forget your password. (y/n)")).to_lowercase().as_str()
+    {
+        "y" | "yes" => Some(generate_recovery_key()),
+        _ => None
+    };
+    let priv_key_recovery: Vec<u8> = match &recovery_key
+    {
+        Some(recovery_key) => pkey
+            .private_key_to_pem_pkcs8_passphrase(cipher, normalize_recovery_key(recovery_key).as_bytes())
+            .unwrap(),
+        None => Vec::new()
+    };
+    let new_account: Account = Account {
+        id,
+        username,
+        hash: b64_pass,
+        salt: salt.to_vec(),
+        algorithm: crypto::ALGORITHM_CURVE25519.to_string(),
+        public_key,
+        priv_key_enc: private_key,
+        signing_key: crypto::signing_key(&pkey).public_key_to_pem().unwrap(),
+        priv_key_recovery,
+        friends: Vec::new(),
+        totp_secret: None,
+        totp_last_step: 0,
+        backup_codes: Vec::new(),
+        devices: vec![device],
+        read_receipts: true
+    };
     // ^^ this is fat as HELL in the database. 33kb for a single user entry!!! Could compress somehow for strict data limits, but not important atm
 
     /*
@@ -88,6 +393,20 @@ fn register_account(addl_message: Option<&str>)
     {
         Ok(_) =>
         {
+            if let Some(recovery_key) = recovery_key
+            {
+                let ui: Vec<String> = vec![
+                    "Your recovery key".to_string(),
+                    "".to_string(),
+                    recovery_key,
+                    "".to_string(),
+                    "Write this down and keep it somewhere safe. It won't be shown again.".to_string(),
+                    "Anyone who has it can reset your password.".to_string(),
+                ];
+                utils::create_ui(&ui, utils::Position::Center);
+                utils::grab_str_input(Some("Press enter to continue."));
+                utils::clear();
+            }
             println!("Account validated. Logging you in...");
             login(&new_account);
         }
@@ -107,7 +426,7 @@ fn login_upass()
         utils::clear();
         utils::addl_message(msg, "red"); 
         let username = utils::grab_str_input(Some("Type your username."));
-        let password = utils::grab_str_input(Some("Type your password."));
+        let password = utils::grab_secret_input(Some("Type your password."));
         if username == "back" || password == "back" {login_init()};
         let mut trip: bool = false;
         let query = Account::get_account(&username);
@@ -115,22 +434,16 @@ fn login_upass()
         {
             Some(account) =>
             {
-                let mut output: [u8; 256] = [0u8; 256];
-                Argon2::default()
-                    .hash_password_into(&password.clone().into_bytes(), &account.salt, &mut output)
-                    .expect("failed to hash password");
-                let base64_encoded = general_purpose::STANDARD.encode(output);
+                let base64_encoded = hash_password(password.as_bytes(), &account.salt);
                 // unsecure. read readme.md
                 if base64_encoded == account.hash
                 {
-                    let private_key: Vec<u8> = Rsa::private_key_from_pem_passphrase(&account.priv_key_enc, &password.into_bytes())
-                        .unwrap()
-                        .private_key_to_pem()
-                        .unwrap();
-                    let mut file = File::create("src/userdata/pkey.key").unwrap();
-                    file.write_all(&private_key)
-                        .expect("failed to write priv key to pkey.key");
-                    login(&account);
+                    if !prompt_second_factor(&account)
+                    {
+                        msg = "Invalid two-factor code.";
+                        continue;
+                    }
+                    unlock_and_login(&account, &password);
                     break;
                 }
                 trip = true;
@@ -153,33 +466,330 @@ fn login_upass()
     }
 }
 
+/// Resets a forgotten password with the account's recovery key, then logs in.
+fn recover_account()
+{
+    let mut msg: String = "Type \"back\" to leave.".to_string();
+    loop
+    {
+        utils::clear();
+        utils::addl_message(&msg, "red");
+        let username: String = utils::grab_str_input(Some("Type your username."));
+        if username == "back" {login_init()};
+        let recovery_key: String = utils::grab_secret_input(Some("Type your recovery key."));
+        let query: Option<(Account, PKey<Private>)> = Account::get_account(&username)
+            .and_then(|x| open_recovery_copy(&x, &recovery_key).map(|y| (x, y)));
+        let (account, pkey) = match query
+        {
+            Some(query) => query,
+            None =>
+            {
+                msg = "Invalid username or recovery key.".to_string();
+                continue;
+            }
+        };
+        if !prompt_second_factor(&account)
+        {
+            msg = "Invalid two-factor code.".to_string();
+            continue;
+        }
+        let new_password: String = utils::grab_secret_input(Some("Type a new password."));
+        if new_password != utils::grab_secret_input(Some("Type your new password again."))
+        {
+            msg = "Passwords do not match.".to_string();
+            continue;
+        }
+        // re-fetch in case a backup code was used up, so the credential swap sees the current hash
+        let account: Account = Account::get_account_by_id(&account.id).unwrap();
+        match rewrap_credentials(&account, &pkey, &new_password)
+        {
+            Ok(()) =>
+            {
+                unlock_device(&account, &pkey);
+                break;
+            }
+            Err(e) => msg = e
+        }
+    }
+}
+
+/// Signs in to an account from the local account cache, so only the password (and a two-factor code, if it's on) has to be typed.
+/// The cached account is checked against the database first.
+fn quick_login(cached: &CachedAccount)
+{
+    utils::clear();
+    let account: Account = match account_cache::revalidate(cached)
+    {
+        Ok(account) => account,
+        Err(e) =>
+        {
+            utils::addl_message(&e, "red");
+            utils::grab_str_input(Some("Press enter to continue."));
+            login_init();
+            return;
+        }
+    };
+    let mut msg: &str = "Type \"back\" to leave.";
+    loop
+    {
+        utils::clear();
+        utils::addl_message(msg, "red");
+        let password: String = utils::grab_secret_input(Some(format!("Type the password for {}.", account.username).as_str()));
+        if password == "back" {login_init()};
+        if !verify_password(&account, &password)
+        {
+            msg = "Invalid password.";
+            continue;
+        }
+        if !prompt_second_factor(&account)
+        {
+            msg = "Invalid two-factor code.";
+            continue;
+        }
+        unlock_and_login(&account, &password);
+        break;
+    }
+}
+
+/// Asks for a two-factor code if the account has two-factor authentication on, and checks it. Returns true if the account passes.
+fn prompt_second_factor(account: &Account) -> bool
+{
+    if account.totp_secret.is_none()
+    {
+        return true;
+    }
+    let code: String = utils::grab_str_input(Some("Type the 6-digit code from your authenticator app, or one of your backup codes."));
+    verify_second_factor(account, &code)
+}
+
+/// Decrypts an account's identity key with its password, then unlocks this device and logs in.
+fn unlock_and_login(account: &Account, password: &str)
+{
+    let identity: PKey<Private> = PKey::private_key_from_pem_passphrase(&account.priv_key_enc, password.as_bytes()).unwrap();
+    unlock_device(account, &identity);
+}
+
+/// Generates a keypair for a new device, signed with the identity key if it's an X25519 one.
+/// The device still has to be added to the account, and its private key saved locally.
+fn new_device(identity: &PKey<Private>, approved: bool) -> (Device, PKey<Private>)
+{
+    let device_key: PKey<Private> = crypto::generate_key();
+    let mut device: Device = Device {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: session::device_name(),
+        public_key: device_key.public_key_to_pem().unwrap(),
+        approved,
+        created: chrono::offset::Local::now().to_string(),
+        signature: Vec::new()
+    };
+    if identity.id() == Id::X25519
+    {
+        device.signature = crypto::sign(identity, &device.signed_data());
+    }
+    (device, device_key)
+}
+
+/// Finds this machine's device for an account, setting one up if there isn't one yet, and waits for it to be approved if it needs to be.
+/// Then unlocks the device key for the session and logs in.
+///
+/// The first device an account gets is approved automatically. Every device after that has to be approved from one that already is,
+/// or with the account's recovery key if there isn't one any more (see `recover_device()`).
+fn unlock_device(account: &Account, identity: &PKey<Private>)
+{
+    let local: Option<(Device, PKey<Private>)> = keystore::read_device_key(&account.id, identity).and_then(|key| {
+        let public_key: Vec<u8> = key.public_key_to_pem().unwrap();
+        account
+            .devices
+            .iter()
+            .find(|x| x.public_key == public_key)
+            .map(|x| (x.clone(), key))
+    });
+    // no key on this machine, or a key for a device that has since been revoked
+    let (device, device_key) = match local
+    {
+        Some(local) => local,
+        None =>
+        {
+            let (device, device_key) = new_device(identity, account.devices.is_empty());
+            keystore::write_device_key(&account.id, &device_key, identity).expect("Error saving device key.");
+            Account::add_device(&account.id, &device).expect("An error occurred adding this device to your account.");
+            (device, device_key)
+        }
+    };
+    if !device.approved && !wait_for_approval(account, &device)
+    {
+        login_init();
+        return;
+    }
+    // conversation keys from before this account had devices are still encrypted with the identity key
+    if let Err(e) = message_relay::migrate_identity_keys(&account.id, identity, &device)
+    {
+        utils::addl_message(&format!("Some conversations could not be moved over to this device: {}", e), "red");
+    }
+    keystore::unlock(&account.id, &device.id, &device_key);
+    login(account);
+}
+
+/// Shows a new device's verification code until the device is approved from another device. Returns false if the user gives up, or the device is revoked instead.
+/// Someone who's lost every device they could approve from can approve this one with their recovery key instead (see `recover_device()`).
+fn wait_for_approval(account: &Account, device: &Device) -> bool
+{
+    let mut msg: String = String::new();
+    loop
+    {
+        utils::clear();
+        let ui: Vec<String> = vec![
+            "New device".to_string(),
+            "".to_string(),
+            "This device has to be approved before it can read your conversations.".to_string(),
+            "On a device you're already signed in on, go to manage account > devices and approve it.".to_string(),
+            "".to_string(),
+            format!("Verification code: {}", device.verification_code()),
+            "".to_string(),
+            "Make sure the code matches on both devices.".to_string(),
+            "".to_string(),
+            "If you don't have a device you can approve it from any more, type \"recover\" to approve it with your recovery key.".to_string(),
+        ];
+        utils::create_ui(&ui, utils::Position::Center);
+        utils::addl_message(&msg, "red");
+        match utils::grab_str_input(Some("Press enter once it's approved, or type \"back\" to leave.")).as_str()
+        {
+            "back" => return false,
+            "recover" =>
+            {
+                let recovery_key: String = utils::grab_secret_input(Some("Type your recovery key. Every other device on your account will be signed out and removed."));
+                // re-fetch, so devices added since this login started are removed too
+                let current: Option<Account> = Account::get_account_by_id(&account.id)
+                    .filter(|x| open_recovery_copy(x, &recovery_key).is_some());
+                let current: Account = match current
+                {
+                    Some(current) => current,
+                    None =>
+                    {
+                        msg = "Invalid recovery key. This device was not approved.".to_string();
+                        continue;
+                    }
+                };
+                match recover_device(&current, device)
+                {
+                    Ok(true) => return true,
+                    Ok(false) =>
+                    {
+                        let _ = keystore::clear_private_key(&account.id);
+                        return false;
+                    }
+                    Err(e) =>
+                    {
+                        msg = format!("An error occurred approving this device: {}", e);
+                        continue;
+                    }
+                }
+            }
+            _ => ()
+        }
+        match Account::get_account_by_id(&account.id).and_then(|x| x.devices.into_iter().find(|y| y.id == device.id))
+        {
+            Some(current) if current.approved => return true,
+            Some(_) => msg = "This device hasn't been approved yet.".to_string(),
+            None =>
+            {
+                let _ = keystore::clear_private_key(&account.id);
+                return false;
+            }
+        }
+    }
+}
+
+/// Approves a device with the account's recovery key, for someone who's lost every device they could approve it from. Returns false if the device was removed first.
+///
+/// Every other device is treated as lost: its sessions are ended, and it's removed along with its conversation keys. This device isn't handed any keys;
+/// every conversation is marked to be re-keyed instead, which the other members' devices do the next time they can (see `message_relay::finish_rekeys()`).
+fn recover_device(account: &Account, device: &Device) -> Result<bool, String>
+{
+    for lost in account.devices.iter().filter(|x| x.id != device.id)
+    {
+        Account::remove_device(&account.id, &lost.id).map_err(|e| e.to_string())?;
+        message_relay::remove_device_keys(&account.id, &lost.id)?;
+    }
+    session::end_all(&account.id).map_err(|e| e.to_string())?;
+    if !Account::approve_device(&account.id, &device.id).map_err(|e| e.to_string())?
+    {
+        return Ok(false);
+    }
+    message_relay::request_rekeys(&account.id)?;
+    Ok(true)
+}
+
 /// Transporter to the messenger class
 fn login(p: &Account)
 {
-    validate_login_info(p);
+    if let Some(account) = validate_login_info(p)
+    {
+        account_cache::remember(&account);
+    }
+    session::start(&p.id);
     messenger_panel::init(p);
 }
 
 /// Runs when the program's first started. Presents the user with options to create an account, login, or leave.
+/// Accounts that have been used on this machine are listed too, for quick sign in.
 pub fn login_init()
 {
     utils::clear();
-    let ui: Vec<String> = vec![
+    let cached: Vec<CachedAccount> = account_cache::load();
+    let mut ui: Vec<String> = vec![
         "Welcome to CRIM.".to_string(),
         "".to_string(),
         "".to_string(),
         "".to_string(),
+    ];
+    if !cached.is_empty()
+    {
+        ui.push("Recent accounts".to_string());
+        ui.push("".to_string());
+        ui.extend(cached.iter().enumerate().map(|(i, x)| format!("{} : {}", i + 1, x.username)));
+        ui.push("".to_string());
+        ui.push("use <number> : sign in to a recent account".to_string());
+        ui.push("forget <number> : remove a recent account from this device".to_string());
+    }
+    ui.extend(vec![
         "register : register an account".to_string(),
         "login : login to an existing account".to_string(),
+        "recover : reset a forgotten password with your recovery key".to_string(),
         "exit : leave CRIM".to_string(),
-    ];
+    ]);
     utils::create_ui(&ui, utils::Position::Center);
-    let selection: (String, String) = utils::grab_opt(None, vec!["register", "login", "exit"]);
+    let selection: (String, String) = utils::grab_opt(None, vec!["use", "forget", "register", "login", "recover", "exit"]);
+    let selected: Option<&CachedAccount> = selection
+        .1
+        .parse::<usize>()
+        .ok()
+        .and_then(|x| x.checked_sub(1))
+        .and_then(|x| cached.get(x));
     match selection.0.as_str()
     {
-        "register" => register_account(None),
+        "use" => match selected
+        {
+            Some(account) => quick_login(account),
+            None => login_init()
+        },
+        "forget" =>
+        {
+            if let Some(account) = selected
+            {
+                account_cache::forget(&account.id);
+            }
+            login_init()
+        }
+        "register" => register_account(),
         "login" => login_upass(),
-        "exit" => std::process::exit(0),
+        "recover" => recover_account(),
+        "exit" =>
+        {
+            // statics aren't dropped on exit, so zero the key by hand
+            keystore::lock();
+            std::process::exit(0)
+        }
         _ => login_init()
     }
 }
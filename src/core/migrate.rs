@@ -0,0 +1,129 @@
+/*
+
+One-off database migrations. These run on startup and should do nothing once the database is up to date.
+
+*/
+
+use super::{key_log, mongo, structs::Account};
+use mongodb::bson::{doc, Bson, Document};
+use std::collections::HashMap;
+
+/// Gives every account a UUID and rewrites every username reference (friends lists, conversation users, key owners and message senders) to that UUID.
+///
+/// Only runs if some account is still missing an ID. Message senders inside the encrypted payloads can't be rewritten, so old messages
+/// keep the username the sender had at the time; the messenger shows those as-is.
+pub fn usernames_to_ids()
+{
+    let accounts = mongo::get_collection("accounts");
+    let needs_migration: bool = accounts
+        .find_one(doc! {"id": {"$exists": false}}, None)
+        .expect("An error occurred querying the database for accounts.")
+        .is_some();
+    if !needs_migration
+    {
+        return;
+    }
+
+    // first give every account an id, and remember which username it belongs to
+    let mut ids: HashMap<String, String> = HashMap::new();
+    for account in accounts.find(None, None).unwrap()
+    {
+        let account: Document = account.unwrap();
+        let username: String = account.get_str("username").unwrap().to_string();
+        let id: String = match account.get_str("id")
+        {
+            Ok(id) => id.to_string(),
+            Err(_) =>
+            {
+                let id: String = uuid::Uuid::new_v4().to_string();
+                accounts
+                    .update_one(doc! {"username": &username}, doc! {"$set": {"id": &id}}, None)
+                    .unwrap();
+                id
+            }
+        };
+        ids.insert(username, id);
+    }
+    // anything that isn't a known username is either already an id or belongs to an account that no longer exists, so leave it alone.
+    let to_id = |x: &Bson| -> Bson {
+        match x.as_str().and_then(|x| ids.get(x))
+        {
+            Some(id) => Bson::String(id.clone()),
+            None => x.clone()
+        }
+    };
+
+    for account in accounts.find(None, None).unwrap()
+    {
+        let account: Document = account.unwrap();
+        let friends: Vec<Bson> = account.get_array("friends").unwrap().iter().map(to_id).collect();
+        accounts
+            .update_one(doc! {"id": account.get_str("id").unwrap()}, doc! {"$set": {"friends": friends}}, None)
+            .unwrap();
+    }
+
+    let conversations = mongo::get_collection("conversations");
+    for convo in conversations.find(None, None).unwrap()
+    {
+        let convo: Document = convo.unwrap();
+        let users: Vec<Bson> = convo.get_array("users").unwrap().iter().map(to_id).collect();
+        let keys: Vec<Bson> = convo
+            .get_array("keys")
+            .unwrap()
+            .iter()
+            .map(|x| {
+                let mut key: Document = x.as_document().unwrap().clone();
+                key.insert("owner", to_id(key.get("owner").unwrap()));
+                Bson::Document(key)
+            })
+            .collect();
+        let messages: Vec<Bson> = convo
+            .get_array("messages")
+            .unwrap()
+            .iter()
+            .map(|x| {
+                let mut message: Document = x.as_document().unwrap().clone();
+                message.insert("sender", to_id(message.get("sender").unwrap()));
+                Bson::Document(message)
+            })
+            .collect();
+        conversations
+            .update_one(
+                doc! {"id": convo.get_str("id").unwrap()},
+                doc! {"$set": {"users": users, "keys": keys, "messages": messages}},
+                None
+            )
+            .unwrap();
+    }
+}
+
+/// Publishes the keys of accounts from before the key log existed, so they can still be encrypted for.
+///
+/// Only accounts with nothing in the log at all are backfilled. Once an account is in the log, any key that isn't has to be added
+/// by whoever changed it, where it shows up in `crim audit-keys`, rather than quietly by the next client to start up.
+/// The one exception is signing keys, which weren't logged at first: an account that has one but has never logged one gets it added once.
+pub fn publish_existing_keys()
+{
+    for account in mongo::get_collection("accounts").find(None, None).unwrap()
+    {
+        let account: Account = Account::from_document(account.unwrap());
+        if key_log::has_entries(&account.id)
+        {
+            let logged: Option<Vec<u8>> = key_log::latest_key(&account.id, key_log::SIGNING_KEY).expect("An error occurred reading the key log.");
+            if logged.is_none() && !account.signing_key.is_empty()
+            {
+                key_log::append(&account.id, key_log::SIGNING_KEY, &account.signing_key).expect("An error occurred publishing keys to the key log.");
+            }
+            continue;
+        }
+        key_log::append(&account.id, "", &account.public_key).expect("An error occurred publishing keys to the key log.");
+        if !account.signing_key.is_empty()
+        {
+            key_log::append(&account.id, key_log::SIGNING_KEY, &account.signing_key).expect("An error occurred publishing keys to the key log.");
+        }
+        for device in &account.devices
+        {
+            key_log::append(&account.id, &device.id, &device.public_key).expect("An error occurred publishing keys to the key log.");
+        }
+    }
+}
@@ -1,4 +1,13 @@
+pub mod account_cache;
+pub mod crypto;
+pub mod fingerprint;
+pub mod key_log;
+pub mod keystore;
 pub mod login;
+pub mod migrate;
 pub mod mongo;
+pub mod password_policy;
+pub mod session;
 pub mod utils;
 pub mod structs;
+pub mod totp;
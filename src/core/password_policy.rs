@@ -0,0 +1,229 @@
+/*
+
+Password strength rules, checked whenever a password is set (registering, changing it, or resetting it with a recovery key).
+
+The policy can be tuned in the .env file:
+PASSWORD_MIN_LENGTH     minimum number of characters (default 10)
+PASSWORD_MIN_ENTROPY    minimum estimated entropy in bits (default 45)
+PASSWORD_DENYLIST       path to a file of extra disallowed passwords, one per line (optional; if it can't be read, only the built-in list is used)
+
+*/
+
+use super::utils;
+use std::fs;
+
+const DEFAULT_MIN_LENGTH: usize = 10;
+const DEFAULT_MIN_ENTROPY: f64 = 45.0;
+
+/// Passwords that show up at the top of every leaked password list. Compared case-insensitively.
+const COMMON_PASSWORDS: &[&str] = &[
+    "123456", "123456789", "12345678", "1234567890", "12345", "1234567", "123123", "111111", "000000", "654321",
+    "password", "password1", "password123", "passw0rd", "qwerty", "qwerty123", "qwertyuiop", "1q2w3e4r", "1qaz2wsx", "asdfghjkl",
+    "abc123", "iloveyou", "letmein", "welcome", "welcome1", "monkey", "dragon", "football", "baseball", "sunshine",
+    "princess", "superman", "master", "shadow", "trustno1", "whatever", "starwars", "admin", "administrator", "login",
+    "changeme", "secret", "hello123", "freedom", "michael", "charlie", "computer", "internet", "zaq12wsx", "default"
+];
+
+pub struct PasswordPolicy
+{
+    pub min_length: usize,
+    pub min_entropy: f64,
+    pub denylist: Vec<String>
+}
+
+impl PasswordPolicy
+{
+    /// Builds the policy from the .env file, falling back to the defaults for anything that isn't set.
+    pub fn from_env() -> PasswordPolicy
+    {
+        let mut denylist: Vec<String> = COMMON_PASSWORDS.iter().map(|x| x.to_string()).collect();
+        if let Ok(path) = dotenv::var("PASSWORD_DENYLIST")
+        {
+            match fs::read_to_string(&path)
+            {
+                Ok(extra) => denylist.extend(extra.lines().map(|x| x.trim().to_lowercase()).filter(|x| !x.is_empty())),
+                Err(e) => utils::addl_message(
+                    format!("Could not read the password denylist at {} ({}), so only the built-in list is being used.", path, e).as_str(),
+                    "yellow"
+                )
+            }
+        }
+        PasswordPolicy {
+            min_length: dotenv::var("PASSWORD_MIN_LENGTH")
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(DEFAULT_MIN_LENGTH),
+            min_entropy: dotenv::var("PASSWORD_MIN_ENTROPY")
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(DEFAULT_MIN_ENTROPY),
+            denylist
+        }
+    }
+
+    /// Checks a password against the policy. Returns every rule it breaks, so they can all be shown at once.
+    pub fn check(&self, password: &str, username: &str) -> Result<(), Vec<String>>
+    {
+        let mut problems: Vec<String> = Vec::new();
+        let lowercase: String = password.to_lowercase();
+        if password.chars().count() < self.min_length
+        {
+            problems.push(format!("Passwords must be at least {} characters long.", self.min_length));
+        }
+        if self.denylist.contains(&lowercase)
+        {
+            problems.push("That password is too common.".to_string());
+        }
+        if !username.is_empty() && lowercase.contains(&username.to_lowercase())
+        {
+            problems.push("Passwords can't contain your username.".to_string());
+        }
+        if estimate_entropy(password) < self.min_entropy
+        {
+            problems.push("That password is too predictable. Try making it longer, or mixing in capitals, numbers and symbols.".to_string());
+        }
+        match problems.is_empty()
+        {
+            true => Ok(()),
+            false => Err(problems)
+        }
+    }
+}
+
+/// A character class for `estimate_entropy()`: whether a character is in it, and how many characters it has.
+type CharClass = (fn(&char) -> bool, u32);
+
+/// Gives a rough estimate of a password's entropy in bits.
+///
+/// Each character is worth log2 of the size of the character pool the password draws from (lowercase, uppercase, digits, symbols, anything else),
+/// and repeated characters only count for half. Runs of 3 or more characters that repeat something earlier in the password ("aaaaaaaa", "Ab1!Ab1!")
+/// or count up or down ("abcdef", "54321") are only worth one character in total, so patterns don't pass on length alone.
+pub fn estimate_entropy(password: &str) -> f64
+{
+    let classes: [CharClass; 5] = [
+        (|x| x.is_ascii_lowercase(), 26),
+        (|x| x.is_ascii_uppercase(), 26),
+        (|x| x.is_ascii_digit(), 10),
+        (|x| x.is_ascii_punctuation() || *x == ' ', 33),
+        (|x| !x.is_ascii(), 100)
+    ];
+    let pool: u32 = classes
+        .iter()
+        .filter(|(class, _)| password.chars().any(|x| class(&x)))
+        .map(|(_, size)| size)
+        .sum();
+    if pool == 0
+    {
+        return 0.0;
+    }
+    let chars: Vec<char> = password.chars().collect();
+    let mut effective_length: f64 = 0.0;
+    let mut i: usize = 0;
+    while i < chars.len()
+    {
+        let pattern: usize = sequence_length(&chars[i..]).max(repeat_length(&chars, i));
+        if pattern >= 3
+        {
+            effective_length += 1.0;
+            i += pattern;
+            continue;
+        }
+        effective_length += if chars[..i].contains(&chars[i]) { 0.5 } else { 1.0 };
+        i += 1;
+    }
+    effective_length * (pool as f64).log2()
+}
+
+/// How many characters from the start of `chars` count up or down by one each step, like "abc" or "987".
+fn sequence_length(chars: &[char]) -> usize
+{
+    if chars.len() < 2
+    {
+        return chars.len();
+    }
+    let step: i64 = chars[1] as i64 - chars[0] as i64;
+    if step.abs() != 1
+    {
+        return 1;
+    }
+    1 + chars.windows(2).take_while(|x| x[1] as i64 - x[0] as i64 == step).count()
+}
+
+/// The length of the longest run starting at `start` that copies characters from earlier in the password.
+/// The copy is allowed to overlap the run itself, so "aaaa" after an "a" counts as one long repeat.
+fn repeat_length(chars: &[char], start: usize) -> usize
+{
+    (0..start)
+        .map(|from| (0..chars.len() - start).take_while(|x| chars[from + x] == chars[start + x]).count())
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn policy() -> PasswordPolicy
+    {
+        PasswordPolicy {
+            min_length: DEFAULT_MIN_LENGTH,
+            min_entropy: DEFAULT_MIN_ENTROPY,
+            denylist: COMMON_PASSWORDS.iter().map(|x| x.to_string()).collect()
+        }
+    }
+
+    #[test]
+    fn strong_passwords_pass()
+    {
+        for password in ["correct horse battery staple", "Tr0ub4dor&3xq", "j8#Lq2!vZp0m", "mauve-otter-47-lantern"]
+        {
+            assert_eq!(policy().check(password, "alice"), Ok(()), "{}", password);
+        }
+    }
+
+    #[test]
+    fn short_common_and_username_passwords_fail()
+    {
+        assert!(policy().check("Xy7!q", "alice").is_err());
+        assert!(policy().check("Password123", "alice").is_err());
+        assert!(policy().check("alice-rides-a-bicycle", "Alice").is_err());
+    }
+
+    #[test]
+    fn problems_are_all_reported()
+    {
+        let problems: Vec<String> = policy().check("qwerty", "qwerty").unwrap_err();
+        assert_eq!(problems.len(), 4);
+    }
+
+    #[test]
+    fn repeated_characters_are_predictable()
+    {
+        assert!(estimate_entropy("aaaaaaaaaaaaaaaaaaaaaaaa") < DEFAULT_MIN_ENTROPY);
+        assert!(estimate_entropy("!!!!!!!!!!!!!!!!!!!!!!!!") < DEFAULT_MIN_ENTROPY);
+        assert!(policy().check("aaaaaaaaaaaaaaaaaaaaaaaa", "alice").is_err());
+    }
+
+    #[test]
+    fn repeated_chunks_are_predictable()
+    {
+        assert!(estimate_entropy("Aa1!Aa1!Aa1!Aa1!Aa1!") < DEFAULT_MIN_ENTROPY);
+        assert!(estimate_entropy("abababababababababab") < DEFAULT_MIN_ENTROPY);
+    }
+
+    #[test]
+    fn sequences_are_predictable()
+    {
+        assert!(estimate_entropy("abcdefghijklmnopqrstuvwxyz") < DEFAULT_MIN_ENTROPY);
+        assert!(estimate_entropy("98765432109876543210") < DEFAULT_MIN_ENTROPY);
+    }
+
+    #[test]
+    fn entropy_grows_with_the_character_pool()
+    {
+        assert_eq!(estimate_entropy(""), 0.0);
+        assert!(estimate_entropy("xkqzmwvt") < estimate_entropy("xKqZmWvT"));
+        assert!(estimate_entropy("xKqZmWvT") < estimate_entropy("xK9Z!WvT"));
+    }
+}
@@ -0,0 +1,127 @@
+/*
+
+Login sessions. Every login creates a session in the database recording the device and when it was created and last used.
+A session that's removed from the database (by logging out, or by being revoked from another device) is over; the client notices the next time it checks.
+
+*/
+
+use super::mongo;
+use mongodb::bson::{self, doc, Document};
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+/// The ID of this client's session, if it's logged in.
+static CURRENT_SESSION: Mutex<Option<String>> = Mutex::new(None);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Session
+{
+    pub id: String,
+    pub account_id: String,
+    pub device: String,
+    pub created: String,
+    pub last_seen: String
+}
+
+impl Session
+{
+    pub fn from_document(doc: &Document) -> Session
+    {
+        Session {
+            id: doc.get_str("id").unwrap().to_string(),
+            account_id: doc.get_str("account_id").unwrap().to_string(),
+            device: doc.get_str("device").unwrap().to_string(),
+            created: doc.get_str("created").unwrap().to_string(),
+            last_seen: doc.get_str("last_seen").unwrap().to_string()
+        }
+    }
+
+    /// Returns true if this is the session the current client is logged in with.
+    pub fn is_current(&self) -> bool { CURRENT_SESSION.lock().unwrap().as_ref() == Some(&self.id) }
+}
+
+/// A human-readable name for this machine, e.g. "desktop (linux)".
+pub fn device_name() -> String
+{
+    let hostname: String = std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .map(|x| x.trim().to_string())
+        .unwrap_or_default();
+    match hostname.is_empty()
+    {
+        true => format!("unknown device ({})", std::env::consts::OS),
+        false => format!("{} ({})", hostname, std::env::consts::OS)
+    }
+}
+
+/// Creates a session for an account on this device, and makes it the current session.
+pub fn start(account_id: &str) -> Session
+{
+    let now: String = chrono::offset::Local::now().to_string();
+    let session: Session = Session {
+        id: uuid::Uuid::new_v4().to_string(),
+        account_id: account_id.to_string(),
+        device: device_name(),
+        created: now.clone(),
+        last_seen: now
+    };
+    mongo::get_collection("sessions")
+        .insert_one(bson::to_document(&session).unwrap(), None)
+        .expect("An error occurred creating a session.");
+    *CURRENT_SESSION.lock().unwrap() = Some(session.id.clone());
+    session
+}
+
+/// Ends the current session, if there is one.
+pub fn end()
+{
+    if let Some(id) = CURRENT_SESSION.lock().unwrap().take()
+    {
+        let _ = mongo::get_collection("sessions").delete_one(doc! {"id": id}, None);
+    }
+}
+
+/// Ends every session an account has, on every device. Used when an account is deleted.
+pub fn end_all(account_id: &str) -> Result<(), mongodb::error::Error>
+{
+    *CURRENT_SESSION.lock().unwrap() = None;
+    mongo::get_collection("sessions")
+        .delete_many(doc! {"account_id": account_id}, None)
+        .map(|_| ())
+}
+
+/// Updates the last seen time of the current session. Returns false if there's no current session or it has been revoked.
+pub fn touch() -> bool
+{
+    let id: String = match CURRENT_SESSION.lock().unwrap().clone()
+    {
+        Some(id) => id,
+        None => return false
+    };
+    mongo::get_collection("sessions")
+        .update_one(doc! {"id": &id}, doc! {"$set": {"last_seen": chrono::offset::Local::now().to_string()}}, None)
+        .map(|x| x.matched_count == 1)
+        .unwrap_or(false)
+}
+
+/// Lists an account's active sessions.
+pub fn list(account_id: &str) -> Vec<Session>
+{
+    mongo::get_collection("sessions")
+        .find(doc! {"account_id": account_id}, None)
+        .unwrap()
+        .map(|x| Session::from_document(&x.unwrap()))
+        .collect()
+}
+
+/// Revokes one of an account's sessions. The client using it is sent back to the login screen the next time it checks its session.
+pub fn revoke(account_id: &str, session_id: &str) -> Result<(), String>
+{
+    match mongo::get_collection("sessions").delete_one(doc! {"id": session_id, "account_id": account_id}, None)
+    {
+        Ok(result) if result.deleted_count == 1 => Ok(()),
+        Ok(_) => Err("That session doesn't exist.".to_string()),
+        Err(e) => Err(e.to_string())
+    }
+}
@@ -7,7 +7,7 @@
 use mongodb::bson;
 use serde::{Deserialize, Serialize};
 
-use super::mongo;
+use super::{crypto, key_log, mongo};
 
 
 //----------------------------------------------//
@@ -19,12 +19,29 @@ use super::mongo;
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct Account
 {
+    pub id: String,
     pub username: String,
     pub hash: String,
     pub salt: Vec<u8>,
+    /// Which kind of keys the account uses (see crypto.rs). Accounts from before this was recorded are RSA.
+    pub algorithm: String,
     pub public_key: Vec<u8>,
     pub priv_key_enc: Vec<u8>,
-    pub friends: Vec<String>
+    /// The Ed25519 public key device keys are signed with. Empty for RSA accounts, which don't sign anything.
+    pub signing_key: Vec<u8>,
+    /// The private key encrypted with the account's recovery key. Empty if the account has no recovery key.
+    pub priv_key_recovery: Vec<u8>,
+    pub friends: Vec<String>,
+    /// Base32 TOTP secret. None if two-factor authentication isn't turned on.
+    pub totp_secret: Option<String>,
+    /// The time step of the last TOTP code used, so a code can't be used twice. 0 if none has been.
+    pub totp_last_step: i64,
+    /// Hashes of the unused two-factor backup codes (see `totp::hash_backup_code()`).
+    pub backup_codes: Vec<String>,
+    /// Every device signed in to the account. Conversation keys are encrypted separately for each approved device.
+    pub devices: Vec<Device>,
+    /// Whether other people in a conversation can see when this account has read their messages. Delivery is shown either way.
+    pub read_receipts: bool
 }
 
 impl Account
@@ -33,6 +50,7 @@ impl Account
     pub fn from_document(doc: bson::Document) -> Account
     {
         Account {
+            id: doc.get_str("id").unwrap_or_default().to_string(),
             username: doc.get_str("username").unwrap().to_string(),
             hash: doc.get_str("hash").unwrap().to_string(),
             salt: doc
@@ -41,6 +59,7 @@ impl Account
                 .iter()
                 .map(|x| x.as_i32().unwrap() as u8)
                 .collect::<Vec<u8>>(),
+            algorithm: doc.get_str("algorithm").unwrap_or(crypto::ALGORITHM_RSA).to_string(),
             public_key: doc
                 .get_array("public_key")
                 .unwrap()
@@ -53,20 +72,55 @@ impl Account
                 .iter()
                 .map(|x| x.as_i32().unwrap() as u8)
                 .collect::<Vec<u8>>(),
+            signing_key: doc
+                .get_array("signing_key")
+                .map(|x| x.iter().map(|x| x.as_i32().unwrap() as u8).collect::<Vec<u8>>())
+                .unwrap_or_default(),
+            priv_key_recovery: doc
+                .get_array("priv_key_recovery")
+                .map(|x| x.iter().map(|x| x.as_i32().unwrap() as u8).collect::<Vec<u8>>())
+                .unwrap_or_default(),
             friends: doc
                 .get_array("friends")
                 .unwrap()
                 .iter()
                 .map(|x| x.as_str().unwrap().to_string())
-                .collect()
+                .collect(),
+            totp_secret: doc.get_str("totp_secret").ok().map(|x| x.to_string()),
+            totp_last_step: doc.get_i64("totp_last_step").unwrap_or_default(),
+            backup_codes: doc
+                .get_array("backup_codes")
+                .map(|x| x.iter().map(|x| x.as_str().unwrap().to_string()).collect())
+                .unwrap_or_default(),
+            devices: doc
+                .get_array("devices")
+                .map(|x| x.iter().map(|x| Device::from_document(x.as_document().unwrap())).collect())
+                .unwrap_or_default(),
+            read_receipts: doc.get_bool("read_receipts").unwrap_or(true)
         }
     }
 
-    /// Takes in a string, finds the matching account in the database, and returns it. Will return none if no account is found, or will panic if it fails to access a database.
-    pub fn get_account(username: &String) -> Option<Account>
+    /// Takes in a username, finds the matching account in the database, and returns it. Will return none if no account is found, or will panic if it fails to access a database.
+    pub fn get_account(username: &String) -> Option<Account> { Account::find_account(bson::doc! { "username": username }) }
+
+    /// Takes in an account ID, finds the matching account in the database, and returns it. Will return none if no account is found, or will panic if it fails to access a database.
+    pub fn get_account_by_id(id: &str) -> Option<Account> { Account::find_account(bson::doc! { "id": id }) }
+
+    /// Returns the current username of the account with the given ID, for displaying. IDs that don't belong to any account are returned as-is,
+    /// since messages sent before the switch to IDs still have usernames in them.
+    pub fn display_name(id: &str) -> String
+    {
+        match Account::get_account_by_id(id)
+        {
+            Some(account) => account.username,
+            None => id.to_string()
+        }
+    }
+
+    fn find_account(filter: bson::Document) -> Option<Account>
     {
         let doc = mongo::get_collection("accounts").find(
-            bson::doc! { "username": username },
+            filter,
             None
         );
         match doc
@@ -83,11 +137,11 @@ impl Account
         }
     }
 
-    /// Takes in an account value reference, and updates the first database entry with the same username. If the update is successful, it will return the account. If not, it will return an error. Most errors from this will likely be from trying to update a non-existent account.
+    /// Takes in an account value reference, and updates the first database entry with the same ID. If the update is successful, it will return the account. If not, it will return an error. Most errors from this will likely be from trying to update a non-existent account.
     pub fn update_account(new: &Account) -> Result<Account, mongodb::error::Error>
     {
         let result = mongo::get_collection("accounts").update_one(
-            bson::doc! { "username": &new.username },
+            bson::doc! { "id": &new.id },
             bson::doc! { "$set": bson::to_document(&new).unwrap() },
             None
         );
@@ -97,7 +151,7 @@ impl Account
             {
                 Ok(Account::from_document(
                     mongo::get_collection("accounts").find_one(
-                        bson::doc! { "username": &new.username },
+                        bson::doc! { "id": &new.id },
                         None
                     )
                     .unwrap()
@@ -109,11 +163,145 @@ impl Account
         }
     } 
 
-    /// Finds the first instance of a database account entry with a given username, and removes it. Returns an empty result.
-    pub fn delete_account(username: &String) -> Result<(), mongodb::error::Error>
+    /// Replaces the password hash, salt and encrypted private key of an account in a single update, but only if the stored hash still matches `old_hash`.
+    /// Returns true if the account was updated, or false if no account matched (i.e. the credentials were changed by someone else in the meantime).
+    pub fn swap_credentials(id: &str, old_hash: &str, hash: &str, salt: &[u8], priv_key_enc: &[u8]) -> Result<bool, mongodb::error::Error>
+    {
+        let result = mongo::get_collection("accounts").update_one(
+            bson::doc! { "id": id, "hash": old_hash },
+            bson::doc! { "$set": {
+                "hash": hash,
+                "salt": bson::to_bson(&salt.to_vec()).unwrap(),
+                "priv_key_enc": bson::to_bson(&priv_key_enc.to_vec()).unwrap()
+            } },
+            None
+        );
+        match result
+        {
+            Ok(result) => Ok(result.matched_count == 1),
+            Err(result) => Err(result)
+        }
+    }
+
+    /// Uses up one of an account's backup codes, by its stored hash. Returns false if the code was already gone, e.g. used by another login at the same time.
+    pub fn use_backup_code(id: &str, stored: &str) -> Result<bool, mongodb::error::Error>
+    {
+        let result = mongo::get_collection("accounts").update_one(
+            bson::doc! { "id": id, "backup_codes": stored },
+            bson::doc! { "$pull": { "backup_codes": stored } },
+            None
+        );
+        match result
+        {
+            Ok(result) => Ok(result.modified_count == 1),
+            Err(result) => Err(result)
+        }
+    }
+
+    /// Records a TOTP time step as used. Returns false if a code from that step or a later one has already been used.
+    pub fn use_totp_step(id: &str, step: i64) -> Result<bool, mongodb::error::Error>
+    {
+        let result = mongo::get_collection("accounts").update_one(
+            bson::doc! { "id": id, "$or": [
+                { "totp_last_step": { "$lt": step } },
+                { "totp_last_step": { "$exists": false } }
+            ] },
+            bson::doc! { "$set": { "totp_last_step": step } },
+            None
+        );
+        match result
+        {
+            Ok(result) => Ok(result.modified_count == 1),
+            Err(result) => Err(result)
+        }
+    }
+
+    /// Finds the first instance of a database account entry with a given ID, and removes it. Returns an empty result.
+    pub fn delete_account(id: &str) -> Result<(), mongodb::error::Error>
     {
         match mongo::get_collection("accounts").delete_one(
-            bson::doc! { "username": username },
+            bson::doc! { "id": id },
+            None
+        )
+        {
+            Ok(_) => Ok(()),
+            Err(result) => Err(result)
+        }
+    }
+
+    /// Removes an account ID from the friends list of every account that has it added. Used when an account is deleted.
+    pub fn remove_from_friend_lists(id: &str) -> Result<(), mongodb::error::Error>
+    {
+        match mongo::get_collection("accounts").update_many(
+            bson::doc! { "friends": id },
+            bson::doc! { "$pull": { "friends": id } },
+            None
+        )
+        {
+            Ok(_) => Ok(()),
+            Err(result) => Err(result)
+        }
+    }
+
+    /// Adds a device to an account, and publishes its key to the key log.
+    pub fn add_device(id: &str, device: &Device) -> Result<(), mongodb::error::Error>
+    {
+        key_log::append(id, &device.id, &device.public_key)?;
+        match mongo::get_collection("accounts").update_one(
+            bson::doc! { "id": id },
+            bson::doc! { "$push": { "devices": bson::to_document(device).unwrap() } },
+            None
+        )
+        {
+            Ok(_) => Ok(()),
+            Err(result) => Err(result)
+        }
+    }
+
+    /// Switches an account over to new keys in a single write: its algorithm, identity key, signing key, encrypted private keys and devices.
+    /// Only applies if the account still has the keys it had when `old` was read. Returns true if it was applied.
+    pub fn replace_keys(old: &Account, new: &Account) -> Result<bool, mongodb::error::Error>
+    {
+        let result = mongo::get_collection("accounts").update_one(
+            bson::doc! { "id": &old.id, "public_key": bson::to_bson(&old.public_key).unwrap() },
+            bson::doc! { "$set": {
+                "algorithm": &new.algorithm,
+                "public_key": bson::to_bson(&new.public_key).unwrap(),
+                "priv_key_enc": bson::to_bson(&new.priv_key_enc).unwrap(),
+                "signing_key": bson::to_bson(&new.signing_key).unwrap(),
+                "priv_key_recovery": bson::to_bson(&new.priv_key_recovery).unwrap(),
+                "devices": bson::to_bson(&new.devices).unwrap()
+            } },
+            None
+        );
+        match result
+        {
+            Ok(result) => Ok(result.matched_count == 1),
+            Err(result) => Err(result)
+        }
+    }
+
+    /// Marks one of an account's devices as approved. Returns true if the device was found.
+    pub fn approve_device(id: &str, device_id: &str) -> Result<bool, mongodb::error::Error>
+    {
+        match mongo::get_collection("accounts").update_one(
+            bson::doc! { "id": id, "devices.id": device_id },
+            bson::doc! { "$set": { "devices.$.approved": true } },
+            None
+        )
+        {
+            Ok(result) => Ok(result.matched_count == 1),
+            Err(result) => Err(result)
+        }
+    }
+
+    /// Removes a device from an account, and records its key as revoked in the key log.
+    pub fn remove_device(id: &str, device_id: &str) -> Result<(), mongodb::error::Error>
+    {
+        key_log::append(id, device_id, &[])?;
+        match mongo::get_collection("accounts").update_one(
+            bson::doc! { "id": id },
+            bson::doc! { "$pull": { "devices": { "id": device_id } } },
             None
         )
         {
@@ -123,8 +311,18 @@ impl Account
     }
 
     /// Creates a new account entry from a given account value ref. Returns the account if successful, or an error if not. Most errors from this will be from faults in database setup.
+    /// The account's keys are published to the key log first.
     pub fn create_account(new: &Account) -> Result<Account, mongodb::error::Error>
     {
+        key_log::append(&new.id, "", &new.public_key)?;
+        if !new.signing_key.is_empty()
+        {
+            key_log::append(&new.id, key_log::SIGNING_KEY, &new.signing_key)?;
+        }
+        for device in &new.devices
+        {
+            key_log::append(&new.id, &device.id, &device.public_key)?;
+        }
         let result = mongo::get_collection("accounts").insert_one(
             bson::to_document(&new).unwrap(),
             None
@@ -133,7 +331,7 @@ impl Account
         {
             Ok(_) => Ok(Account::from_document(
                 mongo::get_collection("accounts").find_one(
-                    bson::doc! { "username": &new.username },
+                    bson::doc! { "id": &new.id },
                     None
                 )
                 .unwrap()
@@ -143,4 +341,71 @@ impl Account
             Err(result) => Err(result)
         }
     }
-}  
+}
+
+
+//----------------------------------------------//
+//                                              //
+//                   Devices                    //
+//                                              //
+//----------------------------------------------//
+
+/// A device signed in to an account. Each device has its own keypair; the private key never leaves the device.
+/// New devices start out unapproved, and only get conversation keys once they're approved from a device that's already approved.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Device
+{
+    pub id: String,
+    pub name: String,
+    pub public_key: Vec<u8>,
+    pub approved: bool,
+    pub created: String,
+    /// The account's signature over the device ID and public key, made with its identity key when the device was added. Empty on RSA accounts.
+    pub signature: Vec<u8>
+}
+
+impl Device
+{
+    /// Parses a BSON Document into a device value
+    pub fn from_document(doc: &bson::Document) -> Device
+    {
+        Device {
+            id: doc.get_str("id").unwrap().to_string(),
+            name: doc.get_str("name").unwrap().to_string(),
+            public_key: doc
+                .get_array("public_key")
+                .unwrap()
+                .iter()
+                .map(|x| x.as_i32().unwrap() as u8)
+                .collect::<Vec<u8>>(),
+            approved: doc.get_bool("approved").unwrap(),
+            created: doc.get_str("created").unwrap().to_string(),
+            signature: doc
+                .get_array("signature")
+                .map(|x| x.iter().map(|x| x.as_i32().unwrap() as u8).collect::<Vec<u8>>())
+                .unwrap_or_default()
+        }
+    }
+
+    /// The bytes a device's signature covers: its ID followed by its public key.
+    pub fn signed_data(&self) -> Vec<u8> { [self.id.as_bytes(), &self.public_key].concat() }
+
+    /// Checks a device was signed by the account's signing key, which has to be the one in the key log.
+    /// Only accounts with no signing key logged (RSA accounts) have unsigned devices. Once one is logged there's no going back, whatever the account's algorithm says.
+    pub fn signature_valid(&self, account: &Account) -> bool
+    {
+        match key_log::latest_key(&account.id, key_log::SIGNING_KEY)
+        {
+            Ok(Some(logged)) => logged == account.signing_key && crypto::verify_signature(&logged, &self.signed_data(), &self.signature),
+            Ok(None) => account.signing_key.is_empty(),
+            Err(_) => false
+        }
+    }
+
+    /// A short code derived from the device's public key. It's shown on both the new device and the approving one, so the user can check they're approving the right device.
+    pub fn verification_code(&self) -> String
+    {
+        let digest: String = hex::encode(openssl::sha::sha256(&self.public_key));
+        format!("{}-{}", &digest[..4], &digest[4..8]).to_uppercase()
+    }
+}
@@ -0,0 +1,191 @@
+/*
+
+Time-based one-time passwords (RFC 6238) for two-factor authentication.
+Codes are 6 digits, HMAC-SHA1, 30 second steps, which is what every authenticator app expects by default.
+Each code only works once: the time step of the last code used is stored with the account, and codes for that step or earlier are turned away.
+
+Backup codes are stored hashed with Argon2 and a random salt each, so a copy of the database isn't enough to work them out.
+
+*/
+
+use super::utils;
+use argon2::Argon2;
+use getrandom::getrandom;
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const STEP_SECONDS: u64 = 30;
+const DIGITS: u32 = 6;
+const BACKUP_CODE_COUNT: usize = 10;
+
+/// Generates a new random 160-bit secret, base32-encoded.
+pub fn generate_secret() -> String
+{
+    let mut secret: [u8; 20] = [0; 20];
+    getrandom(&mut secret).expect("Failed to generate TOTP secret.");
+    utils::base32_encode(&secret)
+}
+
+/// Builds the otpauth:// URI for a secret. This is the string authenticator apps read out of a QR code, and most of them also accept it pasted in directly.
+pub fn otpauth_uri(username: &str, secret: &str) -> String
+{
+    let label: String = username
+        .bytes()
+        .map(|x| match x.is_ascii_alphanumeric() || b"-._~".contains(&x)
+        {
+            true => (x as char).to_string(),
+            false => format!("%{:02X}", x)
+        })
+        .collect();
+    format!("otpauth://totp/CRIM:{}?secret={}&issuer=CRIM&algorithm=SHA1&digits={}&period={}", label, secret, DIGITS, STEP_SECONDS)
+}
+
+/// Checks a code against a secret. Codes from one step either side of now are accepted to allow for clock drift.
+/// Returns the time step the code belongs to, so it can be recorded as used, or none if it doesn't match.
+pub fn verify(secret: &str, code: &str) -> Option<u64>
+{
+    verify_at(secret, code, SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs())
+}
+
+/// `verify()`, for a given Unix time.
+fn verify_at(secret: &str, code: &str, time: u64) -> Option<u64>
+{
+    let code: String = code.chars().filter(|x| !x.is_whitespace()).collect();
+    let secret: Vec<u8> = utils::base32_decode(secret)?;
+    if code.len() != DIGITS as usize || !code.chars().all(|x| x.is_ascii_digit())
+    {
+        return None;
+    }
+    let step: u64 = time / STEP_SECONDS;
+    [step.saturating_sub(1), step, step + 1]
+        .into_iter()
+        .find(|x| format!("{:0width$}", hotp(&secret, *x), width = DIGITS as usize) == code)
+}
+
+/// Generates a set of one-time backup codes, in the `xxxxx-xxxxx` form they're shown to the user in.
+pub fn generate_backup_codes() -> Vec<String>
+{
+    (0..BACKUP_CODE_COUNT)
+        .map(|_| {
+            let mut bytes: [u8; 5] = [0; 5];
+            getrandom(&mut bytes).expect("Failed to generate backup code.");
+            let code: String = hex::encode(bytes);
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// Strips dashes, spaces and case out of a backup code, so it works however it was written down.
+fn normalize_backup_code(code: &str) -> String
+{
+    code.chars()
+        .filter(|x| x.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Hashes a normalized backup code with Argon2.
+fn argon2_backup_code(code: &str, salt: &[u8]) -> [u8; 32]
+{
+    let mut output: [u8; 32] = [0; 32];
+    Argon2::default()
+        .hash_password_into(code.as_bytes(), salt, &mut output)
+        .expect("Failed to hash backup code.");
+    output
+}
+
+/// Hashes a backup code for storage, as "argon2$<salt>$<hash>" in hex.
+pub fn hash_backup_code(code: &str) -> String
+{
+    let mut salt: [u8; 16] = [0; 16];
+    getrandom(&mut salt).expect("Failed to generate salt.");
+    format!("argon2${}${}", hex::encode(salt), hex::encode(argon2_backup_code(&normalize_backup_code(code), &salt)))
+}
+
+/// Checks a typed backup code against one stored by `hash_backup_code()`.
+pub fn backup_code_matches(stored: &str, code: &str) -> bool
+{
+    let code: String = normalize_backup_code(code);
+    match stored.split('$').collect::<Vec<&str>>()[..]
+    {
+        ["argon2", salt, hash] => match hex::decode(salt)
+        {
+            Ok(salt) => hex::encode(argon2_backup_code(&code, &salt)) == hash,
+            Err(_) => false
+        },
+        _ => false
+    }
+}
+
+/// HOTP (RFC 4226) for a given counter.
+fn hotp(secret: &[u8], counter: u64) -> u32
+{
+    let key = PKey::hmac(secret).expect("Failed to create HMAC key.");
+    let mut signer: Signer = Signer::new(MessageDigest::sha1(), &key).expect("Failed to create HMAC signer.");
+    signer.update(&counter.to_be_bytes()).unwrap();
+    let mac: Vec<u8> = signer.sign_to_vec().unwrap();
+    let offset: usize = (mac[mac.len() - 1] & 0x0f) as usize;
+    let binary: u32 = u32::from_be_bytes([mac[offset] & 0x7f, mac[offset + 1], mac[offset + 2], mac[offset + 3]]);
+    binary % 10_u32.pow(DIGITS)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /// The RFC 4226 test secret, "12345678901234567890".
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn hotp_matches_rfc_4226()
+    {
+        let expected: [u32; 10] = [755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489];
+        for (counter, code) in expected.iter().enumerate()
+        {
+            assert_eq!(hotp(SECRET, counter as u64), *code);
+        }
+    }
+
+    #[test]
+    fn totp_matches_rfc_6238()
+    {
+        // the SHA-1 vectors from RFC 6238 appendix B, cut down to 6 digits
+        let secret: String = utils::base32_encode(SECRET);
+        let vectors: [(u64, &str); 6] = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130")
+        ];
+        for (time, code) in vectors
+        {
+            assert_eq!(verify_at(&secret, code, time), Some(time / STEP_SECONDS));
+        }
+    }
+
+    #[test]
+    fn totp_allows_one_step_of_drift()
+    {
+        let secret: String = utils::base32_encode(SECRET);
+        assert_eq!(verify_at(&secret, "287082", 59 + STEP_SECONDS), Some(1));
+        assert_eq!(verify_at(&secret, "287082", 59 + 2 * STEP_SECONDS), None);
+        assert_eq!(verify_at(&secret, "287 082", 59), Some(1));
+        assert_eq!(verify_at(&secret, "28708", 59), None);
+        assert_eq!(verify_at(&secret, "abcdef", 59), None);
+    }
+
+    #[test]
+    fn backup_codes_match_however_they_are_typed()
+    {
+        let stored: String = hash_backup_code("a1b2c-3d4e5");
+        assert!(stored.starts_with("argon2$"));
+        assert!(backup_code_matches(&stored, "a1b2c-3d4e5"));
+        assert!(backup_code_matches(&stored, "A1B2C 3D4E5"));
+        assert!(!backup_code_matches(&stored, "a1b2c-3d4e6"));
+        // salted, so the same code never hashes the same way twice
+        assert_ne!(stored, hash_backup_code("a1b2c-3d4e5"));
+    }
+}
@@ -8,6 +8,8 @@ use colored::Colorize;
 use rand::RngCore;
 use std::io::{self, Write};
 
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
 pub enum Position
 {
     Center,
@@ -83,6 +85,75 @@ pub fn grab_str_input(msg: Option<&str>) -> String
     input
 }
 
+/// Grab a string input without showing it on screen. Used for passwords and recovery keys.
+///
+/// On Unix terminals, echo is turned off with termios while the line is read. If stdin isn't a terminal (e.g. input is piped in) or echo can't be
+/// turned off, it falls back to reading the line normally.
+pub fn grab_secret_input(msg: Option<&str>) -> String
+{
+    let mut input: String = String::new();
+    if let Some(msg) = msg
+    {
+        println!("{}", msg);
+    }
+    io::stdout().flush().unwrap();
+    #[cfg(unix)]
+    let _echo_guard: Option<EchoGuard> = EchoGuard::disable_echo();
+    io::stdin()
+        .read_line(&mut input)
+        .expect("Failed to read line.");
+    input = String::from(input.trim());
+    input
+}
+
+/// Turns terminal echo off for as long as it's alive, and puts the original terminal settings back when dropped.
+#[cfg(unix)]
+struct EchoGuard
+{
+    original: libc::termios
+}
+
+#[cfg(unix)]
+impl EchoGuard
+{
+    /// Returns none if stdin isn't a terminal or its settings couldn't be changed, in which case nothing was touched.
+    fn disable_echo() -> Option<EchoGuard>
+    {
+        // SAFETY: termios is a plain C struct that tcgetattr fills in, and the fd is only used if isatty says it's a terminal.
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) != 1
+            {
+                return None;
+            }
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0
+            {
+                return None;
+            }
+            let original: libc::termios = termios;
+            termios.c_lflag &= !libc::ECHO;
+            termios.c_lflag |= libc::ECHONL; // still echo the enter key so the next output starts on a new line
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) != 0
+            {
+                return None;
+            }
+            Some(EchoGuard { original })
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for EchoGuard
+{
+    fn drop(&mut self)
+    {
+        // SAFETY: restores settings that tcgetattr gave us for the same fd.
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
 /// Grab an option input.
 /// 
 /// Ex:
@@ -96,8 +167,8 @@ pub fn grab_str_input(msg: Option<&str>) -> String
 /// }
 pub fn grab_opt(msg: Option<&str>, mut valid_options: Vec<&str>) -> (String, String)
 {
-    valid_options.sort_by_key(|a| a.len());
-    // sort this by length so that things with flags get read first.
+    valid_options.sort_by_key(|a| std::cmp::Reverse(a.len()));
+    // sort this by length, longest first, so that things with flags get read first.
     loop
     {
         let mut input: String = String::new();
@@ -164,3 +235,84 @@ pub fn rand_hex() -> String
     rand::thread_rng().fill_bytes(&mut bytes);
     hex::encode(bytes)
 }
+
+/// Encodes bytes as unpadded RFC 4648 base32.
+pub fn base32_encode(bytes: &[u8]) -> String
+{
+    let mut output: String = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits: u32 = 0;
+    for byte in bytes
+    {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5
+        {
+            output.push(BASE32_ALPHABET[((buffer >> (bits - 5)) & 31) as usize] as char);
+            bits -= 5;
+        }
+    }
+    if bits > 0
+    {
+        output.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    output
+}
+
+/// Decodes RFC 4648 base32, ignoring case, whitespace and padding. Returns none if the string has characters outside the alphabet.
+pub fn base32_decode(string: &str) -> Option<Vec<u8>>
+{
+    let mut output: Vec<u8> = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits: u32 = 0;
+    for c in string.trim_end_matches('=').chars().filter(|x| !x.is_whitespace())
+    {
+        let value: u32 = BASE32_ALPHABET.iter().position(|x| *x as char == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8
+        {
+            output.push((buffer >> (bits - 8)) as u8);
+            bits -= 8;
+        }
+    }
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /// The base32 vectors from RFC 4648 section 10, without padding.
+    const VECTORS: [(&str, &str); 7] = [
+        ("", ""),
+        ("f", "MY"),
+        ("fo", "MZXQ"),
+        ("foo", "MZXW6"),
+        ("foob", "MZXW6YQ"),
+        ("fooba", "MZXW6YTB"),
+        ("foobar", "MZXW6YTBOI")
+    ];
+
+    #[test]
+    fn base32_encodes_rfc_4648_vectors()
+    {
+        for (input, output) in VECTORS
+        {
+            assert_eq!(base32_encode(input.as_bytes()), output);
+        }
+    }
+
+    #[test]
+    fn base32_decodes_rfc_4648_vectors()
+    {
+        for (output, input) in VECTORS
+        {
+            assert_eq!(base32_decode(input), Some(output.as_bytes().to_vec()));
+        }
+        assert_eq!(base32_decode("mzxw6ytboi======"), Some(b"foobar".to_vec()));
+        assert_eq!(base32_decode("MZXW 6YTB OI"), Some(b"foobar".to_vec()));
+        assert_eq!(base32_decode("MZXW1"), None);
+    }
+}
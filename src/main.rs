@@ -3,6 +3,14 @@ mod messenger;
 
 fn main()
 {
+    // `crim audit-keys` checks the key log and exits, without touching anything
+    if std::env::args().nth(1).as_deref() == Some("audit-keys")
+    {
+        std::process::exit(if core::key_log::audit() { 0 } else { 1 });
+    }
+    core::key_log::create_indexes().expect("An error occurred setting up the key log.");
+    core::migrate::usernames_to_ids();
+    core::migrate::publish_existing_keys();
     core::utils::clear();
     core::login::login_init();
 }
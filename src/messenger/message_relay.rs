@@ -1,10 +1,11 @@
-use std::fs;
-use super::{mongo, structs::Account};
+use super::{crypto, key_log, keystore, mongo, session, structs::{Account, Device}};
 use getrandom::getrandom;
 use mongodb::bson::{self, doc};
-use mongodb::bson::Document;
+use mongodb::bson::{Bson, Document};
+use mongodb::options::{IndexOptions, UpdateOptions};
+use mongodb::IndexModel;
 use openssl::{
-    pkey::{Private, Public}, rsa::{Padding, Rsa}, symm
+    pkey::{PKey, Private}, symm
 };
 use serde::{Deserialize, Serialize};
 
@@ -20,19 +21,59 @@ https://stackoverflow.com/questions/63152965/how-does-the-sender-decrypt-his-own
 //                                              //
 //----------------------------------------------//
 
-#[derive(Serialize, Deserialize)]
+/// A decrypted message. `sender` is the sender's account ID.
+#[derive(Serialize, Deserialize, Default)]
 pub struct RawMessage
 {
+    /// The ID of the EncryptedMessage this came from. Not part of the encrypted payload; it's filled in when the message is decrypted.
+    #[serde(skip)]
+    pub id: String,
     pub sender: String,
     pub message: Vec<u8>,
-    pub time: String
+    pub time: String,
+    /// True for messages the client writes itself to record something that happened, like someone joining or leaving.
+    #[serde(default)]
+    pub system: bool,
+    /// Set on edits: the ID of the message being edited. Edits are sent as messages of their own, and `receive_messages()` folds them into the original.
+    #[serde(default)]
+    pub edit_of: Option<String>,
+    /// Set on replies: the ID of the message being replied to.
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    /// Set on reactions: the ID of the message being reacted to. The emoji is the message text. Sending the same reaction again takes it back.
+    #[serde(default)]
+    pub reaction_to: Option<String>,
+    /// Reactions to this message as (emoji, account IDs of the people who reacted with it), in the order they were first used.
+    /// Not part of the encrypted payload; filled in by `receive_messages()`.
+    #[serde(skip)]
+    pub reactions: Vec<(String, Vec<String>)>,
+    /// Edits to this message as (time, new text), oldest first. Not part of the encrypted payload; filled in by `receive_messages()`.
+    #[serde(skip)]
+    pub edits: Vec<(String, Vec<u8>)>,
+    /// True if the message has been deleted, leaving only a tombstone. Deleted messages have no text or time; only `id`, `sender` and `seq` are filled in.
+    #[serde(skip)]
+    pub deleted: bool,
+    /// The message's sequence number in the conversation (see `EncryptedMessage.seq`). Not part of the encrypted payload.
+    #[serde(skip)]
+    pub seq: i64
+}
+
+impl RawMessage
+{
+    /// The message's current text: the latest edit, or what was sent if it hasn't been edited.
+    pub fn text(&self) -> &[u8] { self.edits.last().map(|x| x.1.as_slice()).unwrap_or(&self.message) }
 }
 
 
+/// A conversation key encrypted for one device. `owner` is the account ID, and `device` is the ID of the device whose public key it was encrypted with.
+///
+/// Keys from before devices existed have an empty `device`, and are encrypted with the account's identity key instead.
+/// They get moved over to a device the next time their owner logs in (see `migrate_identity_keys()`).
 #[derive(Serialize, Deserialize, Clone, Default, Debug)]
 pub struct UserKey
 {
     owner: String,
+    device: String,
     key: Vec<u8>
 }
 
@@ -41,44 +82,87 @@ impl UserKey
     fn from_document(doc: &Document) -> UserKey
     {
         let owner: String = doc.get_str("owner").unwrap().to_string();
+        let device: String = doc.get_str("device").unwrap_or_default().to_string();
         let key: Vec<u8> = doc
             .get_array("key")
             .unwrap()
             .iter()
             .map(|x| x.as_i64().unwrap() as u8)
             .collect();
-        UserKey { owner, key }
+        UserKey { owner, device, key }
+    }
+    /// Encrypts a conversation key for every approved device of an account.
+    /// Accounts with no devices yet (they haven't logged in since devices were added) get a single key encrypted with their identity key.
+    /// Every key is checked against the key log first; nothing is encrypted for keys that aren't in it.
+    fn encrypt(key: &[u8], user: &str) -> Result<Vec<UserKey>, String>
+    {
+        let account: Account = Account::get_account_by_id(user).ok_or("Could not find an account to encrypt for.")?;
+        key_log::verify_account(&account)?;
+        let devices: Vec<&Device> = account.devices.iter().filter(|x| x.approved).collect();
+        if devices.iter().any(|x| !x.signature_valid(&account))
+        {
+            return Err(format!("One of {}'s devices isn't signed by their account. Someone may be tampering with keys.", account.username));
+        }
+        if devices.is_empty()
+        {
+            return Ok(vec![UserKey::encrypt_with(key, user, "", &account.public_key)?]);
+        }
+        devices
+            .iter()
+            .map(|x| UserKey::encrypt_with(key, user, &x.id, &x.public_key))
+            .collect()
     }
-    fn encrypt(key: &[u8], user: &String) -> UserKey
+    fn encrypt_with(key: &[u8], owner: &str, device: &str, public_key: &[u8]) -> Result<UserKey, String>
     {
-        let pub_key: Vec<u8> = Account::get_account(user).unwrap().public_key;
-        let pub_key: Rsa<Public> = Rsa::public_key_from_pem(pub_key.as_slice()).expect("Failed to retrieve a public key from database.");
-        let mut encrypted_key: Vec<u8> = vec![0; pub_key.size() as usize];
-        pub_key
-            .public_encrypt(key, &mut encrypted_key, Padding::PKCS1)
-            .expect("failed to encrypt key");
-        UserKey { owner: user.clone(), key: encrypted_key }
+        Ok(UserKey { owner: owner.to_string(), device: device.to_string(), key: crypto::wrap_key(public_key, key)? })
     }
-    fn decrypt(&self, encrypted_key: &[u8]) -> UserKey
+    fn decrypt(&self, encrypted_key: &[u8]) -> Result<UserKey, String>
     {
-        let priv_key: String = fs::read_to_string("src/userdata/pkey.key").expect("failed to open key file");
-        let priv_key: Rsa<Private> = Rsa::private_key_from_pem(priv_key.as_bytes()).expect("failed to parse private key");
-        let mut decrypted_key: Vec<u8> = vec![0; priv_key.size() as usize];
-        priv_key
-            .private_decrypt(&encrypted_key, &mut decrypted_key, Padding::PKCS1)
-            .expect("failed to decrypt key");
-        UserKey { owner: String::clone(&self.owner), key: decrypted_key }
+        let priv_key: PKey<Private> = keystore::private_key(&self.owner).ok_or("Your private key is not unlocked.")?;
+        Ok(UserKey { owner: String::clone(&self.owner), device: String::clone(&self.device), key: crypto::unwrap_key(&priv_key, encrypted_key)? })
     }
 }
 
+/// Finds the conversation key that belongs to this device, for the given user.
+fn own_key<'a>(convo: &'a Conversation, user_id: &str) -> Option<&'a UserKey>
+{
+    let device_id: String = keystore::device_id(user_id)?;
+    convo
+        .keys
+        .iter()
+        .find(|x| x.owner == user_id && x.device == device_id)
+}
+
+/// Fails if this client's session has ended, e.g. been revoked from another device, so nothing more is written with it.
+/// Checked before every change to a conversation, since the panel only checks when it redraws.
+fn require_session() -> Result<(), String>
+{
+    match session::touch()
+    {
+        true => Ok(()),
+        false => Err("You've been signed out of this session.".to_string())
+    }
+}
+
+/// Converts a value to BSON by way of JSON, so byte arrays end up stored as 64-bit integers like the rest of the conversation document.
+fn to_bson<T: Serialize>(value: &T) -> Bson { Bson::try_from(serde_json::to_value(value).unwrap()).unwrap() }
+
 
 #[derive(Serialize, Deserialize)]
 pub struct EncryptedMessage
 {
+    /// A short random ID, unique within the conversation, that messages are referred to by.
+    pub id: String,
     pub data: Vec<u8>,
     pub sender: String,
     pub dest_convo_id: String,
     pub sender_sid: String,
+    /// True once the message has been deleted. Its ciphertext is thrown away, and only this tombstone is left in its place.
+    #[serde(default)]
+    pub deleted: bool,
+    /// Counts up from 1 within a conversation, and is never reused. Read receipts (see `Receipt`) refer to messages by it.
+    #[serde(default)]
+    pub seq: i64
 }
 
 impl EncryptedMessage
@@ -94,23 +178,92 @@ impl EncryptedMessage
             .map(|x| x.as_i64().unwrap() as u8)
             .collect();
         let sender: String = doc.get_str("sender").unwrap().to_string();
+        // messages from before IDs existed get one from their ciphertext, so it stays the same every time they're loaded
+        let id: String = match doc.get_str("id")
+        {
+            Ok(id) => id.to_string(),
+            Err(_) => hex::encode(openssl::sha::sha256(&data))[..8].to_string()
+        };
+        let deleted: bool = doc.get_bool("deleted").unwrap_or(false);
+        let seq: i64 = doc.get_i64("seq").unwrap_or(0);
         EncryptedMessage {
+            id,
             data,
             sender,
             dest_convo_id: String::new(),
-            sender_sid: String::new()
+            sender_sid: String::new(),
+            deleted,
+            seq
         }
     }
 }
 
 
+/// How far someone has got through a conversation, as the sequence number of the last message (see `EncryptedMessage.seq`).
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct Receipt
+{
+    pub user: String,
+    /// The last message their client has downloaded.
+    pub delivered: i64,
+    /// The last message they've read. Doesn't move while they have read receipts turned off.
+    pub read: i64
+}
+
+impl Receipt
+{
+    fn from_document(doc: &Document) -> Receipt
+    {
+        Receipt {
+            user: doc.get_str("user").unwrap().to_string(),
+            delivered: doc.get_i64("delivered").unwrap_or(0),
+            read: doc.get_i64("read").unwrap_or(0)
+        }
+    }
+}
+
+
+/// What someone can do in a conversation. Each role can do everything the ones before it can.
+///
+/// Members can send messages and delete their own. Admins can also rename the conversation, add and remove members, pin messages,
+/// and delete anyone's messages. The owner can also make people admins, or take it away.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub enum Role
+{
+    Member,
+    Admin,
+    Owner
+}
+
+/// A conversation. `users` holds the account IDs of every participant; `owner` and `admins` say which of them have more than the member role.
 #[derive(Serialize)]
 pub struct Conversation
 {
     pub id: String,
+    /// The group name, if one was given when the conversation was made.
+    pub name: Option<String>,
     pub users: Vec<String>,
+    /// True for one-on-one conversations started with `new <friend>`. There's only ever one of these per pair of people (see `direct_conversation()`).
+    pub direct: bool,
+    /// Set on direct conversations while both people are still in them, to a hash of the pair (see `direct_key()`).
+    /// A unique index on it is what keeps two people starting a conversation at the same time from ending up with two.
+    pub direct_key: Option<String>,
+    pub owner: String,
+    /// Admins other than the owner.
+    pub admins: Vec<String>,
+    /// IDs of pinned messages, oldest pin first.
+    pub pinned: Vec<String>,
     pub keys: Vec<UserKey>,
-    pub messages: Vec<EncryptedMessage>
+    pub messages: Vec<EncryptedMessage>,
+    /// The highest message sequence number handed out so far.
+    pub sequence: i64,
+    /// Delivery and read receipts, one for each member who has opened the conversation.
+    pub receipts: Vec<Receipt>,
+    /// Goes up by one every time the conversation is written to, so a save can tell if someone else has saved it since it was read (see `modify()`).
+    pub version: i64,
+    /// Set when a member recovers their account on a new device, which has no key for the conversation. The next member who can read it
+    /// gives it a new key that the new device gets a copy of (see `finish_rekeys()`).
+    pub rekey: bool
 }
 
 impl Conversation
@@ -118,6 +271,7 @@ impl Conversation
     pub fn from_document(doc: &Document) -> Conversation
     {
         let id: String = doc.get_str("id").unwrap().to_string();
+        let name: Option<String> = doc.get_str("name").ok().map(|x| x.to_string());
         let users: Vec<String> = doc
             .get("users")
             .unwrap()
@@ -126,7 +280,7 @@ impl Conversation
             .iter()
             .map(|x| x.as_str().unwrap().to_string())
             .collect();
-        let messages: Vec<EncryptedMessage> = doc
+        let mut messages: Vec<EncryptedMessage> = doc
             .get("messages")
             .unwrap()
             .as_array()
@@ -142,7 +296,133 @@ impl Conversation
             .iter()
             .map(|x| UserKey::from_document(x.as_document().unwrap()))
             .collect();
-        Conversation { id, users, messages, keys }
+        let mut admins: Vec<String> = doc
+            .get_array("admins")
+            .map(|x| x.iter().map(|y| y.as_str().unwrap().to_string()).filter(|y| users.contains(y)).collect())
+            .unwrap_or_default();
+        // conversations from before roles existed, or whose owner has deleted their account, go to the first admin, or failing that the first member
+        let owner: String = doc
+            .get_str("owner")
+            .ok()
+            .map(|x| x.to_string())
+            .filter(|x| users.contains(x))
+            .or_else(|| admins.first().cloned())
+            .or_else(|| users.first().cloned())
+            .unwrap_or_default();
+        admins.retain(|x| *x != owner);
+        // the only way to make a conversation before groups worked was `new <friend>`, so old two-person conversations are direct ones
+        let direct: bool = doc.get_bool("direct").unwrap_or(users.len() == 2 && name.is_none());
+        let direct_key: Option<String> = doc.get_str("direct_key").ok().map(|x| x.to_string());
+        let pinned: Vec<String> = doc
+            .get_array("pinned")
+            .map(|x| x.iter().map(|y| y.as_str().unwrap().to_string()).collect())
+            .unwrap_or_default();
+        // messages from before sequence numbers existed get numbered in order, and stay that way the next time the conversation is saved
+        let mut last: i64 = 0;
+        for message in messages.iter_mut()
+        {
+            if message.seq <= last
+            {
+                message.seq = last + 1;
+            }
+            last = message.seq;
+        }
+        let sequence: i64 = doc.get_i64("sequence").unwrap_or(0).max(last);
+        let receipts: Vec<Receipt> = doc
+            .get_array("receipts")
+            .map(|x| x.iter().map(|y| Receipt::from_document(y.as_document().unwrap())).filter(|y| users.contains(&y.user)).collect())
+            .unwrap_or_default();
+        let version: i64 = doc.get_i64("version").unwrap_or(0);
+        let rekey: bool = doc.get_bool("rekey").unwrap_or(false);
+        Conversation { id, name, users, direct, direct_key, owner, admins, pinned, messages, keys, sequence, receipts, version, rekey }
+    }
+
+    /// The role someone has in the conversation, or none if they aren't in it.
+    pub fn role(&self, user_id: &str) -> Option<Role>
+    {
+        if !self.users.iter().any(|x| x == user_id)
+        {
+            None
+        }
+        else if self.owner == user_id
+        {
+            Some(Role::Owner)
+        }
+        else if self.admins.iter().any(|x| x == user_id)
+        {
+            Some(Role::Admin)
+        }
+        else
+        {
+            Some(Role::Member)
+        }
+    }
+
+    /// Fails unless the caller has at least the given role. `action` finishes the sentence "Only admins can ...".
+    fn require_role(&self, caller: &str, role: Role, action: &str) -> Result<(), String>
+    {
+        match self.role(caller)
+        {
+            None => Err("You're not in this conversation.".to_string()),
+            Some(actual) if actual < role => match role
+            {
+                Role::Owner => Err(format!("Only the owner can {}.", action)),
+                _ => Err(format!("Only admins can {}.", action))
+            },
+            Some(_) => Ok(())
+        }
+    }
+
+    /// Adds a message to the end of the conversation, giving it the next sequence number.
+    /// Two people sending at once can't get the same one, since only one of their saves goes through and the other retries on top of it (see `modify()`).
+    fn push_message(&mut self, mut message: EncryptedMessage)
+    {
+        self.sequence += 1;
+        message.seq = self.sequence;
+        self.messages.push(message);
+    }
+
+    /// A filter that only matches the stored conversation if nobody has written to it since this copy was read.
+    fn unchanged_filter(&self) -> Document
+    {
+        // conversations from before versions existed don't have one stored
+        match self.version
+        {
+            0 => doc! {"id": &self.id, "version": {"$exists": false}},
+            version => doc! {"id": &self.id, "version": version}
+        }
+    }
+
+    /// A filter that only matches the stored conversation if the caller still has at least the given role in it: either nothing has changed
+    /// since this copy was read (so the role checked against it still holds), or the stored owner and admins still say so.
+    /// Lets an update that only touches one field go through when something unrelated, like a new message, has happened in the meantime.
+    fn role_filter(&self, caller: &str, role: Role) -> Document
+    {
+        let stored: Document = match role
+        {
+            Role::Member => doc! {"users": caller},
+            Role::Admin => doc! {"users": caller, "$or": [{"owner": caller}, {"admins": caller}]},
+            Role::Owner => doc! {"users": caller, "owner": caller}
+        };
+        doc! {"id": &self.id, "$or": [self.unchanged_filter(), stored]}
+    }
+
+    /// Writes the conversation over the stored one, as long as nobody else has written to it since it was read.
+    /// Returns false without writing anything if someone has; use `modify()`, which reads it again and retries.
+    /// Receipts are left as they are; only `update_receipt()` writes them, and it doesn't change the version, so opening a conversation never holds up a save.
+    fn save(&mut self) -> Result<bool, String>
+    {
+        let mut doc: Document = bson::to_document(&serde_json::to_value(&*self).unwrap()).unwrap();
+        doc.remove("receipts");
+        doc.insert("version", self.version + 1);
+        let result = mongo::get_collection("conversations")
+            .update_one(self.unchanged_filter(), doc! {"$set": doc}, None)
+            .map_err(|e| e.to_string())?;
+        if result.matched_count == 1
+        {
+            self.version += 1;
+        }
+        Ok(result.matched_count == 1)
     }
 
     pub fn get(id: &str) -> Option<Conversation>
@@ -166,30 +446,698 @@ impl Conversation
 //                                              //
 //----------------------------------------------//
 
-/// Creates a conversation object and uploads it to the database.
+/// Creates a conversation object between the given account IDs, uploads it to the database, and returns its ID.
 /// The conversation ID contains a unique conversation ID, encrypted with each user's public key. 
 /// For more information, see the diagram in readme.md.
-pub fn create_conversation(users: Vec<String>)
+/// The first user is the one creating it, and becomes its owner. Group conversations can be given a name.
+/// Fails without creating anything if someone's keys don't check out against the key log (see key_log.rs).
+///
+/// Direct conversations should be made with `direct_conversation()` instead, so there's only one per pair.
+pub fn create_conversation(users: Vec<String>, name: Option<String>, direct: bool) -> Result<String, String>
 {
-
-    let mut raw_conversation_key: [u8; 32] = [0; 32];
-    getrandom(&mut raw_conversation_key).expect("Failed to generate random conversation key.");
-    while raw_conversation_key.iter().any(|x| *x == 0_u8) {getrandom(&mut raw_conversation_key).expect("Failed to generate random conversation key.");} // getrandom() can sometimes give a 0, which will fuck everything up.
+    require_session()?;
+    let raw_conversation_key: [u8; 32] = generate_conversation_key();
     
     let conversation = Conversation {
         id: super::utils::rand_hex(),
+        name,
         users: users.clone(),
+        direct,
+        direct_key: if direct { Some(direct_key(&users)) } else { None },
+        owner: users.first().cloned().unwrap_or_default(),
+        admins: Vec::new(),
+        pinned: Vec::new(),
         keys: users
             .clone()
             .iter()
             .map(|x| UserKey::encrypt(&raw_conversation_key, x))
-            .collect(),
-        messages: vec![]
+            .collect::<Result<Vec<Vec<UserKey>>, String>>()?
+            .concat(),
+        messages: vec![],
+        sequence: 0,
+        receipts: Vec::new(),
+        version: 1,
+        rekey: false
     };
-    let doc = bson::to_document(&serde_json::to_value(conversation).unwrap()).unwrap();
+    let doc = bson::to_document(&serde_json::to_value(&conversation).unwrap()).unwrap();
     mongo::get_collection("conversations")
         .insert_one(doc, None)
-        .unwrap();
+        .map(|_| conversation.id)
+        .map_err(|e| e.to_string())
+}
+
+/// The `direct_key` of the direct conversation between a pair of people: a hash of their account IDs, in sorted order so it's the same whoever starts it.
+fn direct_key(users: &[String]) -> String
+{
+    let mut users: Vec<&str> = users.iter().map(|x| x.as_str()).collect();
+    users.sort_unstable();
+    hex::encode(openssl::sha::sha256(users.join("\n").as_bytes()))
+}
+
+/// Returns the ID of the direct conversation between two people, creating it if they don't have one yet.
+pub fn direct_conversation(user_id: &str, friend_id: &str) -> Result<String, String>
+{
+    let conversations = mongo::get_collection("conversations");
+    let key: String = direct_key(&[user_id.to_string(), friend_id.to_string()]);
+    // a direct conversation that one of them has left doesn't count; it has one person in it (and no key), so it won't match
+    let find = || -> Result<Option<String>, String> {
+        let existing: Option<Document> = conversations
+            .find_one(
+                doc! {"$or": [
+                    {"direct_key": &key},
+                    // direct conversations from before keys existed
+                    {"users": {"$all": [user_id, friend_id], "$size": 2}, "direct_key": null, "$or": [{"direct": true}, {"direct": {"$exists": false}, "name": null}]}
+                ]},
+                None
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(existing.map(|x| x.get_str("id").unwrap().to_string()))
+    };
+    if let Some(id) = find()?
+    {
+        return Ok(id);
+    }
+    // the index only covers conversations that have a key, so any number of them can be without one
+    conversations
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! {"direct_key": 1})
+                .options(IndexOptions::builder().unique(true).partial_filter_expression(doc! {"direct_key": {"$type": "string"}}).build())
+                .build(),
+            None
+        )
+        .map_err(|e| e.to_string())?;
+    match create_conversation(vec![user_id.to_string(), friend_id.to_string()], None, true)
+    {
+        // the other person started it at the same moment, so use theirs
+        Err(e) if e.contains("E11000") => find()?.ok_or(e),
+        result => result
+    }
+}
+
+/// How many times `modify()` reads a conversation again after someone else wrote to it first.
+const MODIFY_ATTEMPTS: usize = 5;
+
+/// Reads a conversation, changes it with `change`, and saves it. If someone else wrote to it in between, it's read again
+/// and `change` runs again on the new copy, so nothing they did is lost. Errors from `change` are returned without saving.
+fn modify<T>(convo_id: &str, mut change: impl FnMut(&mut Conversation) -> Result<T, String>) -> Result<T, String>
+{
+    require_session()?;
+    for _ in 0..MODIFY_ATTEMPTS
+    {
+        let mut convo: Conversation = Conversation::get(convo_id).ok_or("Could not find the conversation.")?;
+        let result: T = change(&mut convo)?;
+        if convo.save()?
+        {
+            return Ok(result);
+        }
+    }
+    Err("The conversation kept changing while this was being saved. Try again.".to_string())
+}
+
+/// Generates a new conversation key.
+fn generate_conversation_key() -> [u8; 32]
+{
+    let mut raw_conversation_key: [u8; 32] = [0; 32];
+    getrandom(&mut raw_conversation_key).expect("Failed to generate random conversation key.");
+    raw_conversation_key
+}
+
+/// Decrypts this device's copy of a conversation's key.
+fn conversation_key(convo: &Conversation, user_id: &str) -> Result<Vec<u8>, String>
+{
+    let own_key: &UserKey = match own_key(convo, user_id)
+    {
+        Some(own_key) => own_key,
+        None if convo.rekey => return Err("Waiting for another member to give this device access to the conversation.".to_string()),
+        None => return Err("This device has no key for the conversation.".to_string())
+    };
+    let private_key: PKey<Private> = keystore::private_key(user_id).ok_or("Your private key is not unlocked.")?;
+    crypto::unwrap_key(&private_key, &own_key.key)
+}
+
+/// Gives a conversation a new key, re-encrypting its history with it and wrapping it for everyone in `users`.
+/// Used whenever someone leaves, so they can't read anything sent after they're gone.
+fn rekey(convo: &mut Conversation, old_key: &[u8]) -> Result<(), String>
+{
+    let new_key: [u8; 32] = generate_conversation_key();
+    let cipher: symm::Cipher = symm::Cipher::aes_256_cbc();
+    for message in convo.messages.iter_mut().filter(|x| !x.deleted)
+    {
+        let data: Vec<u8> = symm::decrypt(cipher, old_key, None, &message.data).map_err(|e| e.to_string())?;
+        message.data = symm::encrypt(cipher, &new_key, None, &data).map_err(|e| e.to_string())?;
+    }
+    convo.keys = convo
+        .users
+        .iter()
+        .map(|x| UserKey::encrypt(&new_key, x))
+        .collect::<Result<Vec<Vec<UserKey>>, String>>()?
+        .concat();
+    Ok(())
+}
+
+/// Adds a system message (see `RawMessage.system`) to a conversation. Nothing is saved until the conversation is.
+fn add_system_message(convo: &mut Conversation, caller: &str, text: String) -> Result<(), String>
+{
+    let message: RawMessage = RawMessage {
+        id: String::new(),
+        sender: caller.to_string(),
+        message: text.into_bytes(),
+        time: chrono::offset::Local::now().to_string(),
+        system: true,
+        ..Default::default()
+    };
+    let encrypted: EncryptedMessage = encrypt_message(&message, convo)?;
+    convo.push_message(encrypted);
+    Ok(())
+}
+
+/// Sends a system message (see `RawMessage.system`) to a conversation.
+fn send_system_message(caller: &str, convo_id: &str, text: String) -> Result<(), String>
+{
+    modify(convo_id, |convo| add_system_message(convo, caller, text.clone()))
+}
+
+/// Runs a targeted update on a conversation, one that increments its version along with whatever else it does, so a `modify()` in progress
+/// reads it again rather than writing over it. Returns false if the filter didn't match.
+fn update_conversation(filter: Document, mut update: Document, options: impl Into<Option<UpdateOptions>>) -> Result<bool, String>
+{
+    require_session()?;
+    update.insert("$inc", doc! {"version": 1});
+    mongo::get_collection("conversations")
+        .update_one(filter, update, options)
+        .map(|x| x.matched_count == 1)
+        .map_err(|e| e.to_string())
+}
+
+/// Adds someone to a conversation as a member. Admins only. They can read its history once they're in.
+pub fn add_member(caller: &str, convo_id: &str, member_id: &str) -> Result<(), String>
+{
+    modify(convo_id, |convo| {
+        convo.require_role(caller, Role::Admin, "add people")?;
+        if convo.users.iter().any(|x| x == member_id)
+        {
+            return Err(format!("{} is already in this conversation.", Account::display_name(member_id)));
+        }
+        let key: Vec<u8> = conversation_key(convo, caller)?;
+        let new_keys: Vec<UserKey> = UserKey::encrypt(&key, member_id)?;
+        add_system_message(convo, caller, format!("{} added {}", Account::display_name(caller), Account::display_name(member_id)))?;
+        convo.users.push(member_id.to_string());
+        // with a third person it's a group now, and the original pair can start a new direct conversation
+        convo.direct = false;
+        convo.direct_key = None;
+        convo.keys.extend(new_keys);
+        Ok(())
+    })
+}
+
+/// Removes someone else from a conversation. Admins can remove members, and the owner can remove anyone.
+/// The conversation gets a new key that the removed member doesn't have.
+pub fn remove_member(caller: &str, convo_id: &str, member_id: &str) -> Result<(), String>
+{
+    if caller == member_id
+    {
+        return Err("Use leave to leave a conversation.".to_string());
+    }
+    modify(convo_id, |convo| {
+        convo.require_role(caller, Role::Admin, "remove people")?;
+        match convo.role(member_id)
+        {
+            None => return Err(format!("{} isn't in this conversation.", Account::display_name(member_id))),
+            Some(role) if convo.role(caller).is_some_and(|x| x <= role) => return Err(format!("You can't remove {}.", Account::display_name(member_id))),
+            Some(_) => {}
+        }
+        let key: Vec<u8> = conversation_key(convo, caller)?;
+        add_system_message(convo, caller, format!("{} removed {}", Account::display_name(caller), Account::display_name(member_id)))?;
+        convo.users.retain(|x| x != member_id);
+        convo.admins.retain(|x| x != member_id);
+        convo.direct_key = None;
+        rekey(convo, &key)
+    })
+}
+
+/// Leaves a conversation. The conversation gets a new key that the caller doesn't keep.
+/// If the owner leaves, the longest-standing admin takes over, or the longest-standing member if there are no admins.
+/// The last person to leave deletes the conversation.
+pub fn leave_conversation(caller: &str, convo_id: &str) -> Result<(), String>
+{
+    require_session()?;
+    // only deleted if the caller really is the last one in it, so someone added in the meantime doesn't lose it
+    let deleted = mongo::get_collection("conversations")
+        .delete_one(doc! {"id": convo_id, "users": [caller]}, None)
+        .map_err(|e| e.to_string())?;
+    if deleted.deleted_count == 1
+    {
+        return Ok(());
+    }
+    modify(convo_id, |convo| {
+        if !convo.users.iter().any(|x| x == caller)
+        {
+            return Err("You're not in this conversation.".to_string());
+        }
+        if convo.users.len() == 1
+        {
+            return Err("Everyone else left while you were leaving. Try again.".to_string());
+        }
+        let key: Vec<u8> = conversation_key(convo, caller)?;
+        add_system_message(convo, caller, format!("{} left", Account::display_name(caller)))?;
+        convo.users.retain(|x| x != caller);
+        convo.admins.retain(|x| x != caller);
+        convo.direct_key = None;
+        if convo.owner == caller
+        {
+            convo.owner = match convo.admins.is_empty()
+            {
+                true => convo.users[0].clone(),
+                false => convo.admins.remove(0)
+            };
+        }
+        rekey(convo, &key)
+    })
+}
+
+/// Makes a member an admin, or an admin a member again. Owner only.
+pub fn set_role(caller: &str, convo_id: &str, member_id: &str, admin: bool) -> Result<(), String>
+{
+    let convo: Conversation = Conversation::get(convo_id).ok_or("Could not find the conversation.")?;
+    convo.require_role(caller, Role::Owner, "change people's roles")?;
+    let (update, text): (Document, String) = match (convo.role(member_id), admin)
+    {
+        (None, _) => return Err(format!("{} isn't in this conversation.", Account::display_name(member_id))),
+        (Some(Role::Owner), _) => return Err("The owner's role can't be changed.".to_string()),
+        (Some(Role::Member), true) => (
+            doc! {"$addToSet": {"admins": member_id}},
+            format!("{} made {} an admin", Account::display_name(caller), Account::display_name(member_id))
+        ),
+        (Some(Role::Admin), false) => (
+            doc! {"$pull": {"admins": member_id}},
+            format!("{} made {} a member", Account::display_name(caller), Account::display_name(member_id))
+        ),
+        (Some(_), true) => return Err(format!("{} is already an admin.", Account::display_name(member_id))),
+        (Some(_), false) => return Err(format!("{} isn't an admin.", Account::display_name(member_id)))
+    };
+    let mut filter: Document = convo.role_filter(caller, Role::Owner);
+    filter.insert("users", member_id);
+    if !update_conversation(filter, update, None)?
+    {
+        return Err("The conversation changed before the role could be changed. Try again.".to_string());
+    }
+    send_system_message(caller, convo_id, text)
+}
+
+/// Renames a conversation, or removes its name if `name` is empty. Admins only.
+pub fn rename_conversation(caller: &str, convo_id: &str, name: &str) -> Result<(), String>
+{
+    let convo: Conversation = Conversation::get(convo_id).ok_or("Could not find the conversation.")?;
+    convo.require_role(caller, Role::Admin, "rename the conversation")?;
+    let name: String = name.trim().to_string();
+    let text: String = match name.is_empty()
+    {
+        true => format!("{} removed the conversation name", Account::display_name(caller)),
+        false => format!("{} renamed the conversation to {}", Account::display_name(caller), name)
+    };
+    let stored: Bson = match name.is_empty()
+    {
+        true => Bson::Null,
+        false => Bson::String(name)
+    };
+    if !update_conversation(convo.role_filter(caller, Role::Admin), doc! {"$set": {"name": stored}}, None)?
+    {
+        return Err("The conversation changed before it could be renamed. Try again.".to_string());
+    }
+    send_system_message(caller, convo_id, text)
+}
+
+/// Pins a message, or unpins it if `pin` is false. Admins only.
+pub fn pin_message(caller: &str, convo_id: &str, message_id: &str, pin: bool) -> Result<(), String>
+{
+    let convo: Conversation = Conversation::get(convo_id).ok_or("Could not find the conversation.")?;
+    convo.require_role(caller, Role::Admin, "pin messages")?;
+    match convo.messages.iter().find(|x| x.id == message_id)
+    {
+        None => return Err("That message doesn't exist.".to_string()),
+        Some(message) if message.deleted && pin => return Err("That message has been deleted.".to_string()),
+        _ => {}
+    }
+    let pinned: bool = convo.pinned.iter().any(|x| x == message_id);
+    let mut filter: Document = convo.role_filter(caller, Role::Admin);
+    let update: Document = match (pin, pinned)
+    {
+        (true, false) =>
+        {
+            // it can't have been deleted in the meantime
+            filter.insert("messages", doc! {"$elemMatch": {"id": message_id, "deleted": {"$ne": true}}});
+            doc! {"$addToSet": {"pinned": message_id}}
+        }
+        (false, true) => doc! {"$pull": {"pinned": message_id}},
+        (true, true) => return Err("That message is already pinned.".to_string()),
+        (false, false) => return Err("That message isn't pinned.".to_string())
+    };
+    match update_conversation(filter, update, None)?
+    {
+        true => Ok(()),
+        false => Err("The conversation changed before the message could be pinned. Try again.".to_string())
+    }
+}
+
+/// Deletes a message from a conversation, leaving a tombstone where it was. Anyone can delete their own messages; deleting someone else's needs an admin.
+pub fn delete_message(caller: &str, convo_id: &str, message_id: &str) -> Result<(), String>
+{
+    let convo: Conversation = Conversation::get(convo_id).ok_or("Could not find the conversation.")?;
+    let (sender, deleted): (String, bool) = convo
+        .messages
+        .iter()
+        .find(|x| x.id == message_id)
+        .map(|x| (x.sender.clone(), x.deleted))
+        .ok_or("That message doesn't exist.")?;
+    if deleted
+    {
+        return Err("That message has already been deleted.".to_string());
+    }
+    let (role, action): (Role, &str) = match sender == caller
+    {
+        true => (Role::Member, "delete messages"),
+        false => (Role::Admin, "delete other people's messages")
+    };
+    convo.require_role(caller, role, action)?;
+    // edits have the message's text in them too, so they go with it, along with reactions to it
+    let events: Vec<String> = decrypt_messages(caller, &convo)?
+        .into_iter()
+        .filter(|x| x.edit_of.as_deref() == Some(message_id) || x.reaction_to.as_deref() == Some(message_id))
+        .map(|x| x.id)
+        .collect();
+    let mut filter: Document = convo.role_filter(caller, role);
+    filter.insert("messages", doc! {"$elemMatch": {"id": message_id, "deleted": {"$ne": true}}});
+    let tombstone: Document = doc! {
+        "$set": {"messages.$[message].data": [], "messages.$[message].deleted": true},
+        "$pull": {"pinned": message_id}
+    };
+    let options: UpdateOptions = UpdateOptions::builder().array_filters(vec![doc! {"message.id": message_id}]).build();
+    if !update_conversation(filter, tombstone, options)?
+    {
+        return Err("That message couldn't be deleted. It may have been deleted already.".to_string());
+    }
+    update_conversation(doc! {"id": convo_id}, doc! {"$pull": {"messages": {"id": {"$in": events}}}}, None).map(|_| ())
+}
+
+/// Edits one of the caller's messages, by sending an edit that replaces its text. The original text stays in its edit history.
+pub fn edit_message(caller: &str, convo_id: &str, message_id: &str, text: &str) -> Result<(), String>
+{
+    let convo: Conversation = Conversation::get(convo_id).ok_or("Could not find the conversation.")?;
+    convo.require_role(caller, Role::Member, "edit messages")?;
+    let messages: Vec<RawMessage> = receive_messages(caller, convo_id)?;
+    match messages.iter().find(|x| x.id == message_id)
+    {
+        None => return Err("That message doesn't exist.".to_string()),
+        Some(original) if original.sender != caller || original.system => return Err("You can only edit your own messages.".to_string()),
+        Some(original) if original.deleted => return Err("That message has been deleted.".to_string()),
+        Some(original) if original.text() == text.as_bytes() => return Err("That's what the message already says.".to_string()),
+        _ => {}
+    }
+    let edit: RawMessage = RawMessage {
+        sender: caller.to_string(),
+        message: text.as_bytes().to_vec(),
+        time: chrono::offset::Local::now().to_string(),
+        edit_of: Some(message_id.to_string()),
+        ..Default::default()
+    };
+    upload_message(&edit, convo_id)
+}
+
+/// Sends a reply to a message in a conversation.
+pub fn reply_to_message(caller: &str, convo_id: &str, parent_id: &str, text: &str) -> Result<(), String>
+{
+    let messages: Vec<RawMessage> = receive_messages(caller, convo_id)?;
+    match messages.iter().find(|x| x.id == parent_id)
+    {
+        None => return Err("That message doesn't exist.".to_string()),
+        Some(parent) if parent.system || parent.deleted => return Err("You can't reply to that message.".to_string()),
+        _ => {}
+    }
+    let reply: RawMessage = RawMessage {
+        sender: caller.to_string(),
+        message: text.as_bytes().to_vec(),
+        time: chrono::offset::Local::now().to_string(),
+        reply_to: Some(parent_id.to_string()),
+        ..Default::default()
+    };
+    upload_message(&reply, convo_id)
+}
+
+/// Reacts to a message with an emoji, or takes the reaction back if the caller already reacted with it.
+/// Returns true if the reaction was added, or false if it was removed.
+pub fn react_to_message(caller: &str, convo_id: &str, message_id: &str, emoji: &str) -> Result<bool, String>
+{
+    let messages: Vec<RawMessage> = receive_messages(caller, convo_id)?;
+    let message: &RawMessage = messages
+        .iter()
+        .find(|x| x.id == message_id)
+        .ok_or("That message doesn't exist.")?;
+    if message.deleted
+    {
+        return Err("That message has been deleted.".to_string());
+    }
+    let added: bool = !message
+        .reactions
+        .iter()
+        .any(|x| x.0 == emoji && x.1.iter().any(|y| y == caller));
+    let reaction: RawMessage = RawMessage {
+        sender: caller.to_string(),
+        message: emoji.as_bytes().to_vec(),
+        time: chrono::offset::Local::now().to_string(),
+        reaction_to: Some(message_id.to_string()),
+        ..Default::default()
+    };
+    upload_message(&reaction, convo_id).map(|_| added)
+}
+
+/// Records that the caller's client has downloaded every message in a conversation up to sequence number `seq`, and that they've read them too if `read` is true.
+/// Receipts only ever move forward. They're updated in place, and saving a conversation never writes them, so neither can undo the other.
+pub fn update_receipt(caller: &str, convo_id: &str, seq: i64, read: bool) -> Result<(), String>
+{
+    require_session()?;
+    let conversations = mongo::get_collection("conversations");
+    let mut fields: Document = doc! {"receipts.$.delivered": seq};
+    if read
+    {
+        fields.insert("receipts.$.read", seq);
+    }
+    let result = conversations
+        .update_one(doc! {"id": convo_id, "receipts.user": caller}, doc! {"$max": fields}, None)
+        .map_err(|e| e.to_string())?;
+    if result.matched_count == 0
+    {
+        // first time opening it
+        let receipt: Receipt = Receipt { user: caller.to_string(), delivered: seq, read: if read { seq } else { 0 } };
+        conversations
+            .update_one(
+                doc! {"id": convo_id, "receipts.user": {"$ne": caller}},
+                doc! {"$push": {"receipts": to_bson(&receipt)}},
+                None
+            )
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Removes a user and their conversation key from every conversation they are a part of.
+/// Their past messages are left in place so the other participants keep their history. Conversations left with nobody in them are deleted.
+pub fn remove_user_from_conversations(user_id: &str) -> Result<(), String>
+{
+    let conversations = mongo::get_collection("conversations");
+    conversations
+        .update_many(
+            doc! {"users": user_id},
+            doc! {"$pull": {"users": user_id, "keys": {"owner": user_id}}, "$set": {"direct_key": null}, "$inc": {"version": 1}},
+            None
+        )
+        .map_err(|e| e.to_string())?;
+    conversations
+        .delete_many(doc! {"users": {"$size": 0}}, None)
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Gives a newly approved device access to every conversation its account is in, by decrypting each conversation key with this device's key
+/// and encrypting it again for the new device.
+pub fn share_keys_with_device(user_id: &str, device: &Device) -> Result<(), String>
+{
+    require_session()?;
+    let conversations = mongo::get_collection("conversations");
+    let docs = conversations
+        .find(doc! {"users": user_id}, None)
+        .map_err(|e| e.to_string())?;
+    let private_key: PKey<Private> = keystore::private_key(user_id).ok_or("Your private key is not unlocked.")?;
+    key_log::verify_keys(&[(user_id, &device.id, &device.public_key)])?;
+    for doc in docs
+    {
+        let convo: Conversation = Conversation::from_document(&doc.map_err(|e| e.to_string())?);
+        if convo.keys.iter().any(|x| x.owner == user_id && x.device == device.id)
+        {
+            continue;
+        }
+        let own_key: &UserKey = match own_key(&convo, user_id)
+        {
+            Some(own_key) => own_key,
+            None => continue // this device can't read it either, so there's nothing to share
+        };
+        let raw_key: Vec<u8> = crypto::unwrap_key(&private_key, &own_key.key)?;
+        let new_key: UserKey = UserKey::encrypt_with(&raw_key, user_id, &device.id, &device.public_key)?;
+        conversations
+            .update_one(doc! {"id": &convo.id}, doc! {"$push": {"keys": to_bson(&new_key)}, "$inc": {"version": 1}}, None)
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Removes a device's conversation keys from every conversation its account is in. Used when a device is revoked.
+pub fn remove_device_keys(user_id: &str, device_id: &str) -> Result<(), String>
+{
+    mongo::get_collection("conversations")
+        .update_many(
+            doc! {"users": user_id},
+            doc! {"$pull": {"keys": {"owner": user_id, "device": device_id}}, "$inc": {"version": 1}},
+            None
+        )
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Moves an account's conversation keys from before devices existed (encrypted with the identity key) over to a device.
+/// The device's copy goes in before the identity copy comes out, so the conversation is never left without one.
+pub fn migrate_identity_keys(user_id: &str, identity: &PKey<Private>, device: &Device) -> Result<(), String>
+{
+    let conversations = mongo::get_collection("conversations");
+    let docs = conversations
+        .find(doc! {"users": user_id, "keys": {"$elemMatch": {"owner": user_id, "device": ""}}}, None)
+        .map_err(|e| e.to_string())?;
+    for doc in docs
+    {
+        let convo: Conversation = Conversation::from_document(&doc.map_err(|e| e.to_string())?);
+        let identity_copy: &UserKey = match convo.keys.iter().find(|x| x.owner == user_id && x.device.is_empty())
+        {
+            Some(identity_copy) => identity_copy,
+            None => continue
+        };
+        if !convo.keys.iter().any(|x| x.owner == user_id && x.device == device.id)
+        {
+            let raw_key: Vec<u8> = crypto::unwrap_key(identity, &identity_copy.key)?;
+            let new_key: UserKey = UserKey::encrypt_with(&raw_key, user_id, &device.id, &device.public_key)?;
+            conversations
+                .update_one(doc! {"id": &convo.id}, doc! {"$push": {"keys": to_bson(&new_key)}, "$inc": {"version": 1}}, None)
+                .map_err(|e| e.to_string())?;
+        }
+        conversations
+            .update_one(doc! {"id": &convo.id}, doc! {"$pull": {"keys": {"owner": user_id, "device": ""}}, "$inc": {"version": 1}}, None)
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Marks every conversation an account is in to be re-keyed (see `Conversation.rekey`). Used when the account is recovered on a new device
+/// after losing the rest, so the new device gets keys without anyone keeping a copy it could have opened on its own.
+pub fn request_rekeys(user_id: &str) -> Result<(), String>
+{
+    mongo::get_collection("conversations")
+        .update_many(doc! {"users": user_id}, doc! {"$set": {"rekey": true}, "$inc": {"version": 1}}, None)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Re-keys the conversations waiting for it (see `Conversation.rekey`) that this device can read. Everyone in them gets the new key,
+/// including the devices of members who recovered their accounts. Conversations this device has no key for are left for someone else.
+pub fn finish_rekeys(caller: &str) -> Result<(), String>
+{
+    let device_id: String = keystore::device_id(caller).ok_or("Your private key is not unlocked.")?;
+    let ids: Vec<String> = mongo::get_collection("conversations")
+        .find(doc! {"users": caller, "rekey": true, "keys": {"$elemMatch": {"owner": caller, "device": &device_id}}}, None)
+        .map_err(|e| e.to_string())?
+        .map(|x| x.map(|y| y.get_str("id").unwrap_or_default().to_string()))
+        .collect::<Result<Vec<String>, _>>()
+        .map_err(|e| e.to_string())?;
+    for id in ids
+    {
+        modify(&id, |convo| {
+            // someone else got to it first
+            if !convo.rekey
+            {
+                return Ok(());
+            }
+            let key: Vec<u8> = conversation_key(convo, caller)?;
+            rekey(convo, &key)?;
+            convo.rekey = false;
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+/// A conversation ID, with an account's keys for it before and after upgrading (see `upgrade_conversation_keys()`).
+pub type KeyChange = (String, Vec<UserKey>, Vec<UserKey>);
+
+/// Works out the new conversation keys for an account that's upgrading its keys (see `login::upgrade_keys()`): in every conversation,
+/// all of the account's keys are replaced by one for its new device.
+///
+/// Nothing is written. Each conversation's ID comes back with the account's current keys and the new ones; `add_conversation_keys()`
+/// puts the new ones in alongside the old, and `remove_conversation_keys()` takes either set out again.
+pub fn upgrade_conversation_keys(user_id: &str, old_identity: &PKey<Private>, old_device_key: &PKey<Private>, new_device: &Device) -> Result<Vec<KeyChange>, String>
+{
+    let docs = mongo::get_collection("conversations")
+        .find(doc! {"users": user_id}, None)
+        .map_err(|e| e.to_string())?;
+    let mut updates: Vec<KeyChange> = Vec::new();
+    for doc in docs
+    {
+        let convo: Conversation = Conversation::from_document(&doc.map_err(|e| e.to_string())?);
+        // this device's key if it has one, otherwise the identity copy
+        let raw_key: Option<Vec<u8>> = match own_key(&convo, user_id)
+        {
+            Some(own_key) => Some(crypto::unwrap_key(old_device_key, &own_key.key)?),
+            None => convo
+                .keys
+                .iter()
+                .find(|x| x.owner == user_id && x.device.is_empty())
+                .map(|x| crypto::unwrap_key(old_identity, &x.key))
+                .transpose()?
+        };
+        let raw_key: Vec<u8> = match raw_key
+        {
+            Some(raw_key) => raw_key,
+            None => continue // nothing this device could read before, so nothing to carry over
+        };
+        let old: Vec<UserKey> = convo.keys.iter().filter(|x| x.owner == user_id).cloned().collect();
+        let new: Vec<UserKey> = vec![UserKey::encrypt_with(&raw_key, user_id, &new_device.id, &new_device.public_key)?];
+        updates.push((convo.id, old, new));
+    }
+    Ok(updates)
+}
+
+/// Adds the new keys from `upgrade_conversation_keys()` to each conversation, without touching the ones already there.
+pub fn add_conversation_keys(updates: &[KeyChange]) -> Result<(), String>
+{
+    let conversations = mongo::get_collection("conversations");
+    for (convo_id, _, new) in updates
+    {
+        conversations
+            .update_one(doc! {"id": convo_id}, doc! {"$push": {"keys": {"$each": to_bson(new)}}, "$inc": {"version": 1}}, None)
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Takes either the old keys (`old` true) or the new ones from `upgrade_conversation_keys()` out of each conversation.
+/// Keys are matched exactly, so anything added since they were worked out is left alone.
+pub fn remove_conversation_keys(updates: &[KeyChange], old: bool) -> Result<(), String>
+{
+    let conversations = mongo::get_collection("conversations");
+    for (convo_id, old_keys, new_keys) in updates
+    {
+        let keys: &Vec<UserKey> = if old { old_keys } else { new_keys };
+        conversations
+            .update_one(doc! {"id": convo_id}, doc! {"$pull": {"keys": {"$in": to_bson(keys)}}, "$inc": {"version": 1}}, None)
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
 }
 
 //----------------------------------------------//
@@ -201,51 +1149,35 @@ pub fn create_conversation(users: Vec<String>)
 /// Encrypts a RawMessage value with the conversation's unique key, and returns an EncryptedMessage value.
 /// 
 /// Gets the conversation key from the conversation value that corresponds to the recipient, decrypts it with the sender's private key, serializes the RawMessage, re-encrypts it with the decrypted conversation key, and returns an EncryptedMessage value.
-fn encrypt_message(message: &RawMessage, convo: &Conversation) -> EncryptedMessage
+fn encrypt_message(message: &RawMessage, convo: &Conversation) -> Result<EncryptedMessage, String>
 {
 
-    // first, get the public-key encrypted conversation key that belongs to you
-    let convokey: UserKey = convo
-        .keys
-        .iter()
-        .find(|x| x.owner == message.sender.as_str())
-        .unwrap()
+    // first, get the public-key encrypted conversation key that belongs to this device
+    let convokey: UserKey = own_key(convo, &message.sender)
+        .ok_or("This device has no key for the conversation.")?
         .clone();
 
     // then, decrypt that with your private key
-    let mut decrypted_key: UserKey = UserKey::decrypt(&convokey, &convokey.key);
-    decrypted_key.key = decrypted_key.key.as_slice().to_vec();
-    decrypted_key.key.retain(|&x| x != 0_u8); // thanks, null bytes!
+    let decrypted_key: UserKey = UserKey::decrypt(&convokey, &convokey.key)?;
     // now, serialize the message payload, encrypt that serialized payload, and return the encrypted message object.
-    let serialized_message: String = serde_json::to_string(&message).unwrap();
+    let serialized_message: String = serde_json::to_string(&message).map_err(|e| e.to_string())?;
     let cipher: symm::Cipher = symm::Cipher::aes_256_cbc();
-    println!("{:#?}",decrypted_key.key);
-    let encrypted_message_struct: Vec<u8> = symm::encrypt(cipher, &decrypted_key.key, None, serialized_message.as_bytes()).unwrap();
+    let encrypted_message_struct: Vec<u8> = symm::encrypt(cipher, &decrypted_key.key, None, serialized_message.as_bytes()).map_err(|e| e.to_string())?;
 
     // TODO: you stopped here. start to decrypt the messages next.
-    EncryptedMessage { data: encrypted_message_struct, sender: message.sender.clone(), dest_convo_id: convo.id.clone(), sender_sid: String::new()}
+    Ok(EncryptedMessage { id: super::utils::rand_hex(), data: encrypted_message_struct, sender: message.sender.clone(), dest_convo_id: convo.id.clone(), sender_sid: String::new(), deleted: false, seq: 0})
 }
 
-/// Uploads a RawMessage to a conversation in the database.* This is the entry point for sending a message, as `encrypt()` shouldn't be called directly.
-/// 
-/// *Actually replaces an existing conversation entry with a new one containing the new message, because `update_one()` was a pain in my ass.
+/// Uploads a RawMessage to a conversation in the database. This is the entry point for sending a message, as `encrypt()` shouldn't be called directly.
+///
+/// The message is encrypted with whatever key the conversation has when it's saved, so a rekey that happens at the same time can't leave it unreadable.
 pub fn upload_message(message: &RawMessage, convo_id: &str) -> Result<(), String>
 {
-    match Conversation::get(convo_id)
-    {
-        
-            Some(mut convo) =>
-            {   let message: EncryptedMessage = encrypt_message(message, &convo);
-                convo.messages.push(message);
-                let doc = bson::to_document(&serde_json::to_value(&convo).unwrap()).unwrap();
-                // TODO: make this an implementation of the conversation struct. Conversation::update()
-                mongo::get_collection("conversations")
-                    .replace_one(doc!("id": convo.id), doc, None)
-                    .unwrap();
-                Ok(())
-            }
-            None => Err("Could not find the conversation to uplaod to.".to_string())
-    }
+    modify(convo_id, |convo| {
+        let message: EncryptedMessage = encrypt_message(message, convo)?;
+        convo.push_message(message);
+        Ok(())
+    })
 }
 
 //----------------------------------------------//
@@ -254,39 +1186,30 @@ pub fn upload_message(message: &RawMessage, convo_id: &str) -> Result<(), String
 //                                              //
 //----------------------------------------------//
 
-/// Takes in a reference to an EncryptedMessage value and a private key ref, and spits out a RawMessage decrypted with the provided private key.
-fn decrypt_message(caller: &str, encrypted_message: &EncryptedMessage, private_key: &Rsa<Private>, convo_id: &str) -> RawMessage
+/// Takes in a reference to an EncryptedMessage value and the conversation's decrypted key, and spits out a RawMessage decrypted with that key.
+fn decrypt_message(encrypted_message: &EncryptedMessage, convo_key: &[u8]) -> Result<RawMessage, String>
 {
-    // retrieve conversation object from db
-    let convo: Conversation = 
-        if let Some(convo) = Conversation::get(convo_id) {convo} 
-        else {panic!("Could not find conversation to decrypt message from.")};
-    // decrypt conversation key corresponding to you
-    let mut convokey: UserKey = convo
-        .keys
-        .iter()
-        .find(|x| x.owner == caller)
-        .unwrap()
-        .clone();
-    let mut decrypted_convo_key: Vec<u8> = vec![0; private_key.size() as usize];
-    private_key
-        .private_decrypt(convokey.key.as_slice(), &mut decrypted_convo_key, Padding::PKCS1)
-        .expect("failed to decrypt convo key");
-    convokey.key = decrypted_convo_key.to_vec();
-    convokey.key.retain(|&x| x != 0_u8); // i <3 null bytes
+    // nothing left to decrypt for a deleted message
+    if encrypted_message.deleted
+    {
+        return Ok(RawMessage { id: encrypted_message.id.clone(), sender: encrypted_message.sender.clone(), deleted: true, seq: encrypted_message.seq, ..Default::default() });
+    }
     // decrypt the message with the decrypted conversation key
     let cipher: symm::Cipher = symm::Cipher::aes_256_cbc();
+    let unreadable = |e: String| format!("A message in this conversation couldn't be decrypted: {}", e);
 
-    let decrypted_message: Vec<u8> = symm::decrypt(cipher, convokey.key.as_slice(), None, encrypted_message.data.as_slice()).unwrap();
+    let decrypted_message: Vec<u8> = symm::decrypt(cipher, convo_key, None, encrypted_message.data.as_slice()).map_err(|e| unreadable(e.to_string()))?;
     // deserialize the message
-    let message: RawMessage = serde_json::from_str(&String::from_utf8(decrypted_message).unwrap()).unwrap();
-    message
-
+    let decrypted_message: String = String::from_utf8(decrypted_message).map_err(|e| unreadable(e.to_string()))?;
+    let mut message: RawMessage = serde_json::from_str(&decrypted_message).map_err(|e| unreadable(e.to_string()))?;
+    message.id = encrypted_message.id.clone();
+    message.seq = encrypted_message.seq;
+    Ok(message)
 }
 
-/// Takes in a conversation ID and returns a Result, either containing a Vec of RawMessages containing all decrypted messages, or a string if no messages were present in the conversation.
+/// Takes in the caller's account ID and a conversation ID and returns a Result, either containing a Vec of RawMessages containing all decrypted messages, or a string if no messages were present in the conversation.
 ///
-/// Finds a conversation matching the conversation id, reads the user's private key from pkey.key, and decrypts all messages in the conversation value.
+/// Finds a conversation matching the conversation id, takes the user's unlocked private key from the key store, and decrypts all messages in the conversation value.
 
 pub fn receive_messages(caller: &str, convo_id: &str) -> Result<Vec<RawMessage>, String>
 {
@@ -297,14 +1220,160 @@ pub fn receive_messages(caller: &str, convo_id: &str) -> Result<Vec<RawMessage>,
             Some(doc) =>
             {
                 let conversation: Conversation = Conversation::from_document(&doc);
-                let mut messages: Vec<RawMessage> = vec![];
-                let key: String = fs::read_to_string("src/userdata/pkey.key").expect("failed to open key file");
-                let key = Rsa::private_key_from_pem(key.as_bytes()).unwrap();
-                messages.extend(conversation.messages.iter().map(|x| decrypt_message(caller, x, &key, convo_id)));
-                Ok(messages)
+                decrypt_conversation(caller, &conversation)
             }
             None => Err("conversation not found".to_string())
         },
         Err(e) => Err(e.to_string())
     }
 }
+
+/// Decrypts the messages in a conversation that's already been fetched, with edits and reactions folded in like `receive_messages()`.
+/// Fails instead of panicking if this device can't decrypt it, so it's safe to use on a whole list of conversations.
+pub fn decrypt_conversation(caller: &str, convo: &Conversation) -> Result<Vec<RawMessage>, String>
+{
+    Ok(apply_events(decrypt_messages(caller, convo)?))
+}
+
+/// Decrypts every message in a conversation exactly as it was sent, edits included.
+fn decrypt_messages(caller: &str, convo: &Conversation) -> Result<Vec<RawMessage>, String>
+{
+    let key: Vec<u8> = conversation_key(convo, caller)?;
+    convo
+        .messages
+        .iter()
+        .map(|x| decrypt_message(x, &key))
+        .collect()
+}
+
+/// Folds edits and reactions into the messages they're for, and leaves them out of the list.
+/// Edits from anyone but the original sender are ignored, as are edits and reactions to messages that aren't there.
+fn apply_events(messages: Vec<RawMessage>) -> Vec<RawMessage>
+{
+    let mut folded: Vec<RawMessage> = Vec::new();
+    for message in messages
+    {
+        match (&message.edit_of, &message.reaction_to)
+        {
+            (Some(id), _) =>
+            {
+                if let Some(original) = folded.iter_mut().find(|x| x.id == *id && x.sender == message.sender && !x.system && !x.deleted)
+                {
+                    original.edits.push((message.time, message.message));
+                }
+            }
+            (None, Some(id)) =>
+            {
+                if let Some(original) = folded.iter_mut().find(|x| x.id == *id && !x.deleted)
+                {
+                    toggle_reaction(&mut original.reactions, String::from_utf8_lossy(&message.message).to_string(), message.sender);
+                }
+            }
+            (None, None) => folded.push(message)
+        }
+    }
+    folded
+}
+
+/// Adds someone's reaction to a message's reactions, or takes it away if they'd already reacted with that emoji.
+fn toggle_reaction(reactions: &mut Vec<(String, Vec<String>)>, emoji: String, sender: String)
+{
+    match reactions.iter_mut().find(|x| x.0 == emoji)
+    {
+        Some((_, senders)) if senders.contains(&sender) => senders.retain(|x| *x != sender),
+        Some((_, senders)) => senders.push(sender),
+        None => reactions.push((emoji, vec![sender]))
+    }
+    reactions.retain(|x| !x.1.is_empty());
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn message(id: &str, sender: &str, text: &str) -> RawMessage
+    {
+        RawMessage { id: id.to_string(), sender: sender.to_string(), message: text.as_bytes().to_vec(), time: format!("time {}", id), ..Default::default() }
+    }
+
+    fn edit(id: &str, sender: &str, of: &str, text: &str) -> RawMessage { RawMessage { edit_of: Some(of.to_string()), ..message(id, sender, text) } }
+
+    fn reaction(id: &str, sender: &str, to: &str, emoji: &str) -> RawMessage { RawMessage { reaction_to: Some(to.to_string()), ..message(id, sender, emoji) } }
+
+    #[test]
+    fn edits_fold_into_the_original()
+    {
+        let folded: Vec<RawMessage> = apply_events(vec![
+            message("a", "alice", "helo"),
+            edit("b", "alice", "a", "hello"),
+            edit("c", "alice", "a", "hello!")
+        ]);
+        assert_eq!(folded.len(), 1);
+        assert_eq!(folded[0].text(), b"hello!");
+        assert_eq!(folded[0].message, b"helo");
+        assert_eq!(folded[0].edits, vec![("time b".to_string(), b"hello".to_vec()), ("time c".to_string(), b"hello!".to_vec())]);
+    }
+
+    #[test]
+    fn edits_from_anyone_else_are_ignored()
+    {
+        let folded: Vec<RawMessage> = apply_events(vec![message("a", "alice", "hello"), edit("b", "mallory", "a", "goodbye")]);
+        assert_eq!(folded.len(), 1);
+        assert_eq!(folded[0].text(), b"hello");
+    }
+
+    #[test]
+    fn edits_to_system_or_deleted_messages_are_ignored()
+    {
+        let system: RawMessage = RawMessage { system: true, ..message("a", "alice", "alice left") };
+        let deleted: RawMessage = RawMessage { deleted: true, ..message("b", "alice", "") };
+        let folded: Vec<RawMessage> = apply_events(vec![system, deleted, edit("c", "alice", "a", "x"), edit("d", "alice", "b", "y")]);
+        assert_eq!(folded.len(), 2);
+        assert!(folded.iter().all(|x| x.edits.is_empty()));
+    }
+
+    #[test]
+    fn events_before_their_message_are_ignored()
+    {
+        let folded: Vec<RawMessage> = apply_events(vec![edit("a", "alice", "b", "x"), reaction("c", "bob", "b", "👍"), message("b", "alice", "hello")]);
+        assert_eq!(folded.len(), 1);
+        assert!(folded[0].edits.is_empty());
+        assert!(folded[0].reactions.is_empty());
+    }
+
+    #[test]
+    fn reactions_toggle()
+    {
+        let folded: Vec<RawMessage> = apply_events(vec![
+            message("a", "alice", "hello"),
+            reaction("b", "bob", "a", "👍"),
+            reaction("c", "carol", "a", "👍"),
+            reaction("d", "bob", "a", "🎉"),
+            reaction("e", "bob", "a", "👍")
+        ]);
+        assert_eq!(folded.len(), 1);
+        assert_eq!(
+            folded[0].reactions,
+            vec![("👍".to_string(), vec!["carol".to_string()]), ("🎉".to_string(), vec!["bob".to_string()])]
+        );
+    }
+
+    #[test]
+    fn reactions_to_deleted_messages_are_ignored()
+    {
+        let deleted: RawMessage = RawMessage { deleted: true, ..message("a", "alice", "") };
+        let folded: Vec<RawMessage> = apply_events(vec![deleted, reaction("b", "bob", "a", "👍")]);
+        assert!(folded[0].reactions.is_empty());
+    }
+
+    #[test]
+    fn taking_back_the_last_reaction_removes_the_emoji()
+    {
+        let mut reactions: Vec<(String, Vec<String>)> = Vec::new();
+        toggle_reaction(&mut reactions, "🔥".to_string(), "bob".to_string());
+        assert_eq!(reactions, vec![("🔥".to_string(), vec!["bob".to_string()])]);
+        toggle_reaction(&mut reactions, "🔥".to_string(), "bob".to_string());
+        assert!(reactions.is_empty());
+    }
+}
@@ -1,15 +1,21 @@
 use super::{
-    message_relay::{self, receive_messages, Conversation, RawMessage}, 
+    message_relay::{self, receive_messages, Conversation, RawMessage, Role}, 
+    account_cache,
+    crypto,
+    fingerprint::{self, Verification},
+    keystore,
+    session::{self, Session},
     mongo, 
     utils,
     login,
-    structs::Account
+    totp,
+    structs::{Account, Device}
 };
+use chrono::{DateTime, FixedOffset};
 use colored::Colorize;
 use mongodb::bson::{doc, Document};
-use std::io::BufWriter;
+use std::collections::{HashMap, HashSet};
 use std::vec;
-use std::fs::File;
 
 //----------------------------------------------//
 //                                              //
@@ -20,6 +26,7 @@ use std::fs::File;
 /// Draws the home page for the messenger. This is the first page the user sees when they log in.
 pub fn draw_home_ui(user: &Account)
 {
+    require_session();
     utils::clear();
     let welcome_message: String = format!("Welcome, {}.", &user.username);
     let ui: Vec<String> = vec![
@@ -55,8 +62,8 @@ pub fn draw_home_ui(user: &Account)
         }
         "logout" =>
         {
-            let f = File::create("src/userdata/pkey.key").expect("no pkey file");
-            serde_json::to_writer(BufWriter::new(f), "").expect("Failed to empty private key. Ensure pkey.key exists.");
+            session::end();
+            keystore::lock();
             login::login_init();
         }
         _ => {}
@@ -66,19 +73,27 @@ pub fn draw_home_ui(user: &Account)
 /// Draws the friend management panel UI. User can add or remove friends here.
 pub fn draw_friend_mgmt_ui(user: &Account)
 {
-    let user: Account = Account::get_account(&user.username).unwrap(); // the user arg can be trusted to have a proper username but not proper friends.
+    require_session();
+    let user: Account = Account::get_account_by_id(&user.id).unwrap(); // the user arg can be trusted to have a proper id but not proper friends.
     let friends: &Vec<String> = &user.friends;
     let mut ui: Vec<String> = vec!["Friends Management".to_string(), "".to_string(), "".to_string()];
     for friend in friends
     {
-        ui.push(friend.to_string());
+        let status: &str = match Account::get_account_by_id(friend).map(|x| fingerprint::status(&user.id, &x))
+        {
+            Some(Verification::Verified) => " (verified)",
+            Some(Verification::Changed) => " (KEYS CHANGED)",
+            _ => ""
+        };
+        ui.push(format!("{}{}", Account::display_name(friend), status));
     }
     ui.push("".to_string());
     ui.push("add <friend> : adds friend by username".to_string());
     ui.push("rm <friend> : removes friend by username".to_string());
+    ui.push("fingerprint <friend> : shows a friend's key fingerprint".to_string());
     ui.push("back : returns to home page".to_string());
     utils::create_ui(&ui, utils::Position::Center);
-    let opt: (String, String) = utils::grab_opt(Some("Please input your option."), vec!["add", "rm", "back"]);
+    let opt: (String, String) = utils::grab_opt(Some("Please input your option."), vec!["add", "rm", "fingerprint", "back"]);
     match opt.0.as_str()
     {
         "add" =>
@@ -113,6 +128,16 @@ pub fn draw_friend_mgmt_ui(user: &Account)
                 draw_friend_mgmt_ui(&user);
             }
         }
+        "fingerprint" =>
+        {
+            utils::clear();
+            match Account::get_account(&opt.1).filter(|x| friends.contains(&x.id))
+            {
+                Some(friend) => draw_fingerprint_ui(&friend),
+                None => utils::addl_message(format!("You don't have {} added as a friend.", opt.1.blue()).as_str(), "red")
+            }
+            draw_friend_mgmt_ui(&user);
+        }
         "back" =>
         {
             draw_home_ui(&user);
@@ -121,10 +146,25 @@ pub fn draw_friend_mgmt_ui(user: &Account)
     }
 }
 
+/// Shows an account's key fingerprint, and waits for the user to press enter.
+fn draw_fingerprint_ui(account: &Account)
+{
+    let ui: Vec<String> = vec![
+        format!("Fingerprint for {}", account.username),
+        "".to_string(),
+        fingerprint::fingerprint(account),
+        "".to_string(),
+        "This changes whenever their keys change, including when they approve a new device.".to_string(),
+    ];
+    utils::create_ui(&ui, utils::Position::Center);
+    utils::grab_str_input(Some("Press enter to continue."));
+    utils::clear();
+}
+
 /// Draws the list of conversations that the user is an active participant in.
-// TODO: add a way to leave conversations
 fn draw_convo_list_ui(user: &Account)
 {
+    require_session();
     /*
     Draws the direct conversation panel, which lists all the conversations the user is a part of.
     User can go back with the back command, or open a conversation with a given ID.
@@ -137,14 +177,23 @@ fn draw_convo_list_ui(user: &Account)
         "back : return to message panel".to_string(),
         "".to_string(),
     ];
+    // give members who recovered their accounts keys for anything this device can read, before listing it
+    if let Err(e) = message_relay::finish_rekeys(&user.id)
+    {
+        utils::addl_message(&format!("Some conversations could not be re-keyed: {}", e), "yellow");
+    }
     let conversations: mongodb::sync::Cursor<Document> = mongo::get_collection("conversations")
-        .find(doc!("users": &user.username), None)
+        .find(doc!("users": &user.id), None)
         .unwrap();
-    let conversation_strings: Vec<String> = conversations.into_iter()
-        .map(|x| Conversation::from_document(&x.unwrap()))
-        .map(|y| format!("{} : {}", y.id, y.users.join(", ")))
+    // these would otherwise be looked up once per conversation and once per member
+    let read_receipts: bool = Account::get_account_by_id(&user.id).map(|x| x.read_receipts).unwrap_or(true);
+    let mut names: HashMap<String, String> = HashMap::new();
+    let mut summaries: Vec<(Option<DateTime<FixedOffset>>, Vec<String>)> = conversations.into_iter()
+        .map(|x| summarize_conversation(user, &Conversation::from_document(&x.unwrap()), read_receipts, &mut names))
         .collect();
-    ui.extend(conversation_strings);
+    // most recent activity first, and conversations with nothing in them last
+    summaries.sort_by_key(|x| std::cmp::Reverse(x.0));
+    ui.extend(summaries.into_iter().flat_map(|x| x.1));
     
     utils::create_ui(&ui, utils::Position::Center);
     let opt = utils::grab_opt(None, vec!["open", "back"]);
@@ -187,6 +236,72 @@ fn draw_convo_list_ui(user: &Account)
     }
 }
 
+/// Summarizes a conversation for the conversation list: the ID, name or members, and unread count, then the latest message with its time and sender.
+/// Also returns when the conversation was last active, for sorting. Messages are decrypted locally, on this device.
+/// Display names are cached in `names` so each member is only looked up once per listing.
+fn summarize_conversation(
+    user: &Account,
+    convo: &Conversation,
+    read_receipts: bool,
+    names: &mut HashMap<String, String>,
+) -> (Option<DateTime<FixedOffset>>, Vec<String>)
+{
+    let mut name_of = |id: &str| -> String
+    {
+        names.entry(id.to_string()).or_insert_with(|| Account::display_name(id)).clone()
+    };
+    let members: String = convo.users.iter().map(|x| name_of(x)).collect::<Vec<String>>().join(", ");
+    let title: String = match &convo.name
+    {
+        Some(name) => format!("{} : {} ({})", convo.id, name, members),
+        None => format!("{} : {}", convo.id, members)
+    };
+    let messages: Vec<RawMessage> = match message_relay::decrypt_conversation(&user.id, convo)
+    {
+        Ok(messages) => messages,
+        Err(e) => return (None, vec![title, format!("    {}", e)])
+    };
+    // someone with read receipts off never moves their read marker, so go by what they've had delivered instead; that only moves when they open the conversation
+    let last_read: i64 = convo
+        .receipts
+        .iter()
+        .find(|x| x.user == user.id)
+        .map(|x| if read_receipts { x.read } else { x.delivered })
+        .unwrap_or(0);
+    let unread: usize = messages
+        .iter()
+        .filter(|x| x.seq > last_read && x.sender != user.id && !x.system && !x.deleted)
+        .count();
+    let mut lines: Vec<String> = vec![match unread
+    {
+        0 => title,
+        n => format!("{} | {} unread", title, n)
+    }];
+    let latest_activity: Option<DateTime<FixedOffset>> = messages
+        .iter()
+        .flat_map(|x| std::iter::once(&x.time).chain(x.edits.iter().map(|y| &y.0)))
+        .filter_map(|x| DateTime::parse_from_str(x, "%Y-%m-%d %H:%M:%S%.f %:z").ok())
+        .max();
+    if let Some(latest) = messages.last()
+    {
+        let sender: String = name_of(&latest.sender);
+        let text: String = String::from_utf8_lossy(latest.text()).to_string();
+        let mut preview: String = text.chars().take(40).collect();
+        if preview.len() < text.len()
+        {
+            preview.push_str("...");
+        }
+        let time: String = latest.time.chars().take(16).collect(); // "YYYY-MM-DD HH:MM"
+        lines.push(match (latest.system, latest.deleted)
+        {
+            (true, _) => format!("    {} * {}", time, preview),
+            (false, true) => format!("    {}: message deleted", sender),
+            (false, false) => format!("    {} {}: {}", time, sender, preview)
+        });
+    }
+    (latest_activity, lines)
+}
+
 /// Draws the actual messenger UI where users send and receive messages.
 fn draw_messenger_ui(user: &Account, convo: &Conversation)
 {
@@ -195,42 +310,264 @@ fn draw_messenger_ui(user: &Account, convo: &Conversation)
     */
     loop
     {
+        require_session();
+        // re-fetch every time around, since people can join or leave (or remove the user) while it's open
+        let current: Conversation = match Conversation::get(&convo.id).filter(|x| x.users.contains(&user.id))
+        {
+            Some(current) => current,
+            None =>
+            {
+                utils::clear();
+                utils::addl_message("You're no longer in that conversation.", "red");
+                draw_convo_list_ui(user);
+                return;
+            }
+        };
+        let convo: &Conversation = &current;
+        let role: Role = convo.role(&user.id).unwrap();
+        let members: Vec<String> = convo
+            .users
+            .iter()
+            .map(|x| match convo.role(x)
+            {
+                Some(Role::Owner) => format!("{} (owner)", Account::display_name(x)),
+                Some(Role::Admin) => format!("{} (admin)", Account::display_name(x)),
+                _ => Account::display_name(x)
+            })
+            .collect();
         let mut ui: Vec<String> = vec!
         [
-            "Messenger".to_string(),
-            "".to_string(),
+            convo.name.clone().unwrap_or("Messenger".to_string()),
+            format!("Members: {}", members.join(", ")),
             "".to_string()
         ];
-        let messages: Vec<RawMessage> = receive_messages(&user.username, convo.id.as_str()).unwrap();
-        for message in messages
-        {
-            let messagecontent: String = String::from_utf8(message.message).unwrap();
-            // would be cool to color username but it adds hidden characters, maybe work around it
-            let message: String = format!("{}: {}", message.sender, messagecontent)
-                .as_str()
-                .trim()
-                .to_string();
+        // a verified contact whose keys changed could be someone else entirely, so make sure the user sees it before they send anything
+        for member in convo.users.iter().filter(|x| **x != user.id).filter_map(|x| Account::get_account_by_id(x))
+        {
+            if fingerprint::status(&user.id, &member) == Verification::Changed
+            {
+                utils::addl_message(format!("WARNING: {}'s keys have changed since you verified them. Compare safety numbers again before trusting this conversation.", member.username).as_str(), "red");
+            }
+        }
+        let messages: Vec<RawMessage> = match receive_messages(&user.id, convo.id.as_str())
+        {
+            Ok(messages) => messages,
+            Err(e) =>
+            {
+                utils::clear();
+                utils::addl_message(&e, "red");
+                draw_convo_list_ui(user);
+                return;
+            }
+        };
+        // everything on screen counts as delivered, and as read too unless the user has turned read receipts off
+        if convo.sequence > 0
+        {
+            let read_receipts: bool = Account::get_account_by_id(&user.id).map(|x| x.read_receipts).unwrap_or(true);
+            let _ = message_relay::update_receipt(&user.id, &convo.id, convo.sequence, read_receipts);
+        }
+        let mut names: HashMap<String, String> = HashMap::new(); // sender id -> username, so each sender is only looked up once
+        let pinned: Vec<&RawMessage> = convo
+            .pinned
+            .iter()
+            .filter_map(|x| messages.iter().find(|y| y.id == *x))
+            .collect();
+        if !pinned.is_empty()
+        {
+            ui.push("Pinned:".to_string());
+            for message in pinned
+            {
+                ui.push(format_message(message, &mut names));
+            }
+            ui.push("".to_string());
+        }
+        for message in &messages
+        {
+            if let Some(parent) = &message.reply_to
+            {
+                ui.push(format_quote(messages.iter().find(|x| x.id == *parent), &mut names));
+            }
+            let reactions: Option<String> = format_reactions(message);
+            let message: String = format_message(message, &mut names);
             println!("{}", message.len());
             ui.push(message);
+            ui.extend(reactions);
+        }
+        if let Some(last) = messages.last().filter(|x| !x.system)
+        {
+            ui.extend(format_receipts(convo, last, &user.id));
         }
 
         ui.push("".to_string());
         ui.push("send <message> : send a message".to_string());
+        ui.push("reply <id> <message> : reply to a message".to_string());
+        ui.push("thread <id> : see a message and its replies".to_string());
+        ui.push("react <id> <emoji> : react to a message, or take your reaction back (:thumbsup: style shortcodes work too)".to_string());
+        ui.push("edit <id> <message> : change what one of your messages says".to_string());
+        ui.push("history <id> : see every version of an edited message".to_string());
+        ui.push(match role
+        {
+            Role::Member => "delete <id> : delete one of your messages".to_string(),
+            _ => "delete <id> : delete a message".to_string()
+        });
+        ui.push("verify : compare safety numbers with the people in this conversation".to_string());
+        if role >= Role::Admin
+        {
+            ui.push("rename <name> : rename this conversation (leave the name out to remove it)".to_string());
+            ui.push("invite <friend> : add a friend to this conversation".to_string());
+            ui.push("remove <member> : remove someone from this conversation".to_string());
+            ui.push("pin <id> : pin a message".to_string());
+            ui.push("unpin <id> : unpin a message".to_string());
+        }
+        if role == Role::Owner
+        {
+            ui.push("promote <member> : make someone an admin".to_string());
+            ui.push("demote <member> : make an admin a member again".to_string());
+        }
+        ui.push("leave : leave this conversation".to_string());
         ui.push("back : return to conversation list".to_string());
         utils::create_ui(&ui, utils::Position::Center);
-        let opt: (String, String) = utils::grab_opt(None, vec!["send", "back"]);
+        let opt: (String, String) = utils::grab_opt(None, vec!["send", "reply", "thread", "react", "edit", "history", "delete", "verify", "rename", "invite", "remove", "pin", "unpin", "promote", "demote", "leave", "back"]);
         match opt.0.as_str()
         {
             "send" =>
             {
                 let message = RawMessage
                 { 
-                    sender: user.username.clone(), 
+                    sender: user.id.clone(), 
                     message: opt.1.as_bytes().to_vec(), 
-                    time: chrono::offset::Local::now().to_string()
+                    time: chrono::offset::Local::now().to_string(),
+                    ..Default::default()
                 };
-                message_relay::upload_message(&message, &convo.id).expect("failed to upload message");
-                draw_messenger_ui(user, convo)
+                utils::clear();
+                show_result(message_relay::upload_message(&message, &convo.id), "Message sent.");
+            }
+            "edit" =>
+            {
+                utils::clear();
+                match opt.1.split_once(' ')
+                {
+                    Some((id, text)) if !text.trim().is_empty() => show_result(message_relay::edit_message(&user.id, &convo.id, id, text.trim()), "Message edited."),
+                    _ => utils::addl_message("Usage: edit <id> <message>", "red")
+                }
+            }
+            "reply" =>
+            {
+                utils::clear();
+                match opt.1.split_once(' ')
+                {
+                    Some((id, text)) if !text.trim().is_empty() => show_result(message_relay::reply_to_message(&user.id, &convo.id, id, text.trim()), "Reply sent."),
+                    _ => utils::addl_message("Usage: reply <id> <message>", "red")
+                }
+            }
+            "react" =>
+            {
+                utils::clear();
+                match opt.1.split_once(' ').map(|(id, x)| (id, reaction_emoji(x.trim())))
+                {
+                    Some((id, Some(emoji))) => match message_relay::react_to_message(&user.id, &convo.id, id, &emoji)
+                    {
+                        Ok(true) => utils::addl_message(format!("Reacted with {}.", emoji).as_str(), "green"),
+                        Ok(false) => utils::addl_message(format!("Took back your {} reaction.", emoji).as_str(), "green"),
+                        Err(e) => utils::addl_message(e.as_str(), "red")
+                    },
+                    Some((_, None)) => utils::addl_message("That isn't an emoji or a shortcode CRIM knows.", "red"),
+                    None => utils::addl_message("Usage: react <id> <emoji>", "red")
+                }
+            }
+            "thread" =>
+            {
+                utils::clear();
+                match messages.iter().find(|x| x.id == opt.1 && !x.system)
+                {
+                    Some(message) => draw_thread_ui(message, &messages, &mut names),
+                    None => utils::addl_message("That message doesn't exist.", "red")
+                }
+            }
+            "history" =>
+            {
+                utils::clear();
+                match messages.iter().find(|x| x.id == opt.1 && !x.system && !x.deleted)
+                {
+                    Some(message) => draw_history_ui(message, &mut names),
+                    None => utils::addl_message("That message doesn't exist.", "red")
+                }
+            }
+            "verify" =>
+            {
+                utils::clear();
+                draw_safety_numbers_ui(user, convo);
+            }
+            "invite" =>
+            {
+                utils::clear();
+                let friends: Vec<String> = Account::get_account_by_id(&user.id).unwrap().friends;
+                match Account::get_account(&opt.1).filter(|x| friends.contains(&x.id))
+                {
+                    Some(friend) => match message_relay::add_member(&user.id, &convo.id, &friend.id)
+                    {
+                        Ok(()) => utils::addl_message(format!("Added {} to the conversation.", friend.username.blue()).as_str(), "green"),
+                        Err(e) => utils::addl_message(e.as_str(), "red")
+                    },
+                    None => utils::addl_message(format!("You don't have {} added as a friend.", opt.1.blue()).as_str(), "red")
+                }
+            }
+            "remove" =>
+            {
+                utils::clear();
+                match find_member(convo, &opt.1)
+                {
+                    Some(member) => match message_relay::remove_member(&user.id, &convo.id, &member.id)
+                    {
+                        Ok(()) => utils::addl_message(format!("Removed {} from the conversation.", member.username.blue()).as_str(), "green"),
+                        Err(e) => utils::addl_message(e.as_str(), "red")
+                    },
+                    None => utils::addl_message(format!("{} isn't in this conversation.", opt.1.blue()).as_str(), "red")
+                }
+            }
+            "promote" | "demote" =>
+            {
+                utils::clear();
+                match find_member(convo, &opt.1)
+                {
+                    Some(member) => show_result(message_relay::set_role(&user.id, &convo.id, &member.id, opt.0 == "promote"), "Role changed."),
+                    None => utils::addl_message(format!("{} isn't in this conversation.", opt.1.blue()).as_str(), "red")
+                }
+            }
+            "rename" =>
+            {
+                utils::clear();
+                show_result(message_relay::rename_conversation(&user.id, &convo.id, &opt.1), "Conversation renamed.");
+            }
+            "pin" | "unpin" =>
+            {
+                utils::clear();
+                let pin: bool = opt.0 == "pin";
+                show_result(message_relay::pin_message(&user.id, &convo.id, &opt.1, pin), if pin { "Message pinned." } else { "Message unpinned." });
+            }
+            "delete" =>
+            {
+                utils::clear();
+                show_result(message_relay::delete_message(&user.id, &convo.id, &opt.1), "Message deleted.");
+            }
+            "leave" =>
+            {
+                let confirmation: String = utils::grab_str_input(Some("Are you sure you want to leave this conversation? (y/n)"));
+                utils::clear();
+                if !matches!(confirmation.to_lowercase().as_str(), "y" | "yes")
+                {
+                    continue;
+                }
+                match message_relay::leave_conversation(&user.id, &convo.id)
+                {
+                    Ok(()) =>
+                    {
+                        utils::addl_message("You left the conversation.", "green");
+                        draw_convo_list_ui(user);
+                        return;
+                    }
+                    Err(e) => utils::addl_message(e.as_str(), "red")
+                }
             }
             "back" =>
             {
@@ -242,22 +579,274 @@ fn draw_messenger_ui(user: &Account, convo: &Conversation)
     }
 }
 
+/// Formats a message for the messenger as "[id] sender: text", or "* text" for system messages. Edited messages show their latest text, marked "(edited)",
+/// and deleted ones just say "message deleted".
+/// `names` caches sender ID -> username, so each sender is only looked up once.
+fn format_message(message: &RawMessage, names: &mut HashMap<String, String>) -> String
+{
+    let mut messagecontent: String = String::from_utf8_lossy(message.text()).to_string();
+    if !message.edits.is_empty()
+    {
+        messagecontent.push_str(" (edited)");
+    }
+    let sender: &String = names
+        .entry(message.sender.clone())
+        .or_insert_with(|| Account::display_name(&message.sender));
+    // would be cool to color username but it adds hidden characters, maybe work around it
+    let message: String = match (message.system, message.deleted)
+    {
+        (true, _) => format!("* {}", messagecontent),
+        (false, true) => format!("[{}] {}: message deleted", message.id, sender),
+        (false, false) => format!("[{}] {}: {}", message.id, sender, messagecontent)
+    };
+    message.trim().to_string()
+}
+
+/// Shortcodes that can be used in place of typing an emoji.
+const SHORTCODES: [(&str, &str); 12] = [
+    ("thumbsup", "👍"),
+    ("+1", "👍"),
+    ("thumbsdown", "👎"),
+    ("-1", "👎"),
+    ("heart", "❤️"),
+    ("joy", "😂"),
+    ("smile", "😄"),
+    ("open_mouth", "😮"),
+    ("cry", "😢"),
+    ("angry", "😠"),
+    ("tada", "🎉"),
+    ("fire", "🔥")
+];
+
+/// Turns what the user typed after `react <id>` into an emoji: either a shortcode like ":thumbsup:", or an emoji typed directly.
+/// Returns none for unknown shortcodes, and for anything that doesn't look like an emoji (letters, digits or spaces).
+fn reaction_emoji(input: &str) -> Option<String>
+{
+    if let Some(code) = input.strip_prefix(':').and_then(|x| x.strip_suffix(':'))
+    {
+        return SHORTCODES.iter().find(|x| x.0 == code).map(|x| x.1.to_string());
+    }
+    match input.is_empty() || input.chars().count() > 8 || input.chars().any(|x| x.is_ascii_alphanumeric() || x.is_whitespace())
+    {
+        true => None,
+        false => Some(input.to_string())
+    }
+}
+
+/// Formats the reactions to a message as one line of emoji and counts, e.g. "    👍 2  🎉 1". Returns none if nobody has reacted.
+fn format_reactions(message: &RawMessage) -> Option<String>
+{
+    if message.reactions.is_empty()
+    {
+        return None;
+    }
+    let counts: Vec<String> = message
+        .reactions
+        .iter()
+        .map(|(emoji, senders)| format!("{} {}", emoji, senders.len()))
+        .collect();
+    Some(format!("    {}", counts.join("  ")))
+}
+
+/// Formats the receipt line shown under the last message: "seen by" whoever has read it, or failing that "delivered to" whoever has downloaded it.
+/// Leaves out the sender and the user looking at it. Returns none if nobody else has got that far yet.
+fn format_receipts(convo: &Conversation, message: &RawMessage, viewer: &str) -> Option<String>
+{
+    let others: Vec<&message_relay::Receipt> = convo
+        .receipts
+        .iter()
+        .filter(|x| x.user != message.sender && x.user != viewer)
+        .collect();
+    let seen: Vec<String> = others
+        .iter()
+        .filter(|x| x.read >= message.seq)
+        .map(|x| Account::display_name(&x.user))
+        .collect();
+    if !seen.is_empty()
+    {
+        return Some(format!("    seen by {}", seen.join(", ")));
+    }
+    let delivered: Vec<String> = others
+        .iter()
+        .filter(|x| x.delivered >= message.seq)
+        .map(|x| Account::display_name(&x.user))
+        .collect();
+    match delivered.is_empty()
+    {
+        true => None,
+        false => Some(format!("    delivered to {}", delivered.join(", ")))
+    }
+}
+
+/// Formats the quoted snippet shown above a reply, e.g. "  > sender: the start of the parent message...".
+fn format_quote(parent: Option<&RawMessage>, names: &mut HashMap<String, String>) -> String
+{
+    let parent: &RawMessage = match parent
+    {
+        Some(parent) if !parent.deleted => parent,
+        Some(_) => return "  > message deleted".to_string(),
+        None => return "  > message not found".to_string()
+    };
+    let sender: &String = names
+        .entry(parent.sender.clone())
+        .or_insert_with(|| Account::display_name(&parent.sender));
+    let text: String = String::from_utf8_lossy(parent.text()).to_string();
+    let mut snippet: String = text.chars().take(40).collect();
+    if snippet.len() < text.len()
+    {
+        snippet.push_str("...");
+    }
+    format!("  > {}: {}", sender, snippet)
+}
+
+/// Shows a message and everything replying to it, with replies to replies indented under them. Waits for the user to press enter.
+fn draw_thread_ui(message: &RawMessage, messages: &[RawMessage], names: &mut HashMap<String, String>)
+{
+    let mut ui: Vec<String> = vec!["Thread".to_string(), "".to_string(), format_message(message, names)];
+    // walk down the replies depth-first, keeping track of how far in each one is.
+    // reply_to comes from the sender, so a message can claim to reply to itself or to one of its own replies; each one is only shown once
+    let mut stack: Vec<(&RawMessage, usize)> = vec![(message, 0)];
+    let mut visited: HashSet<&str> = HashSet::new();
+    while let Some((parent, depth)) = stack.pop()
+    {
+        if !visited.insert(parent.id.as_str())
+        {
+            continue;
+        }
+        if depth > 0
+        {
+            ui.push(format!("{}{}", "    ".repeat(depth), format_message(parent, names)));
+        }
+        let replies = messages.iter().filter(|x| x.reply_to.as_deref() == Some(parent.id.as_str()));
+        stack.extend(replies.rev().map(|x| (x, depth + 1)));
+    }
+    if ui.len() == 3
+    {
+        ui.push("No replies yet.".to_string());
+    }
+    utils::create_ui(&ui, utils::Position::Center);
+    utils::grab_str_input(Some("Press enter to continue."));
+    utils::clear();
+}
+
+/// Shows every version of a message, oldest first, and waits for the user to press enter.
+fn draw_history_ui(message: &RawMessage, names: &mut HashMap<String, String>)
+{
+    let sender: &String = names
+        .entry(message.sender.clone())
+        .or_insert_with(|| Account::display_name(&message.sender));
+    let mut ui: Vec<String> = vec![format!("Edit history for [{}] from {}", message.id, sender), "".to_string()];
+    ui.push(format!("{} (sent): {}", message.time, String::from_utf8_lossy(&message.message)));
+    for (time, text) in &message.edits
+    {
+        ui.push(format!("{} (edited): {}", time, String::from_utf8_lossy(text)));
+    }
+    utils::create_ui(&ui, utils::Position::Center);
+    utils::grab_str_input(Some("Press enter to continue."));
+    utils::clear();
+}
+
+/// Finds someone in a conversation by username.
+fn find_member(convo: &Conversation, username: &str) -> Option<Account> { Account::get_account(&username.to_string()).filter(|x| convo.users.contains(&x.id)) }
+
+/// Shows the outcome of an action: `success` in green if it worked, or the error in red if it didn't.
+fn show_result(result: Result<(), String>, success: &str)
+{
+    match result
+    {
+        Ok(()) => utils::addl_message(success, "green"),
+        Err(e) => utils::addl_message(e.as_str(), "red")
+    }
+}
+
+/// Draws the safety numbers for everyone else in a conversation. Once a safety number has been compared with the other person, they can be marked as verified.
+fn draw_safety_numbers_ui(user: &Account, convo: &Conversation)
+{
+    require_session();
+    let user: Account = Account::get_account_by_id(&user.id).unwrap(); // fresh copy, since our own devices are part of the safety number
+    let members: Vec<Account> = convo
+        .users
+        .iter()
+        .filter(|x| **x != user.id)
+        .filter_map(|x| Account::get_account_by_id(x))
+        .collect();
+    let mut ui: Vec<String> = vec!["Safety Numbers".to_string(), "".to_string(), "".to_string()];
+    for (i, member) in members.iter().enumerate()
+    {
+        let status: &str = match fingerprint::status(&user.id, member)
+        {
+            Verification::Verified => "verified",
+            Verification::Changed => "KEYS CHANGED SINCE VERIFIED",
+            Verification::Unverified => "not verified"
+        };
+        ui.push(format!("{} : {} | {}", i + 1, member.username, status));
+        ui.push(fingerprint::safety_number(&user, member));
+        ui.push("".to_string());
+    }
+    ui.push("Compare these with each person in person or over a call. If they match, nobody has swapped out your keys.".to_string());
+    ui.push("".to_string());
+    ui.push("verify <number> : mark someone as verified".to_string());
+    ui.push("unverify <number> : remove someone's verification".to_string());
+    ui.push("back : return to the conversation".to_string());
+    utils::create_ui(&ui, utils::Position::Center);
+    let opt: (String, String) = utils::grab_opt(Some("Please input your option."), vec!["verify", "unverify", "back"]);
+    let selected: Option<&Account> = opt.1.parse::<usize>().ok().and_then(|x| x.checked_sub(1)).and_then(|x| members.get(x));
+    match opt.0.as_str()
+    {
+        "verify" =>
+        {
+            utils::clear();
+            match selected
+            {
+                Some(member) =>
+                {
+                    fingerprint::verify(&user.id, member);
+                    utils::addl_message(format!("{} is now verified.", member.username.blue()).as_str(), "green");
+                }
+                None => utils::addl_message("That person isn't in this conversation.", "red")
+            }
+            draw_safety_numbers_ui(&user, convo);
+        }
+        "unverify" =>
+        {
+            utils::clear();
+            match selected
+            {
+                Some(member) =>
+                {
+                    fingerprint::unverify(&user.id, &member.id);
+                    utils::addl_message(format!("{} is no longer verified.", member.username.blue()).as_str(), "green");
+                }
+                None => utils::addl_message("That person isn't in this conversation.", "red")
+            }
+            draw_safety_numbers_ui(&user, convo);
+        }
+        "back" =>
+        {
+            utils::clear();
+            draw_messenger_ui(&user, convo);
+        }
+        _ => {}
+    }
+}
+
 /// Draws the home UI for the messenger, where users can open new conversations or view existing ones.
 fn draw_messenger_home_ui(user: &Account)
 {
+    require_session();
     /*
     Draws the messenger home UI, which will let users start conversations or view the ones they're a part of.
     */
 
-    let user: Account = Account::get_account(&user.username).unwrap();
+    let user: Account = Account::get_account_by_id(&user.id).unwrap();
     let friends: &Vec<String> = &user.friends;
     let ui: Vec<String> = vec![
         "Message Panel".to_string(),
         "".to_string(),
         "".to_string(),
         "".to_string(),
-        "new <friend> : start a new single conversation with a friend.".to_string(),
-        "new --multi <friend, friend> : start a new multi-person conversation.".to_string(),
+        "new <friend> : open your conversation with a friend, starting one if you don't have one yet.".to_string(),
+        "new --multi <friend, friend> [--name <name>] : start a new multi-person conversation, optionally with a name.".to_string(),
         "open : view open conversations you are a participant in.".to_string(),
         "back : return to home page.".to_string(),
     ];
@@ -268,12 +857,21 @@ fn draw_messenger_home_ui(user: &Account)
         "new" =>
         {
             let friend: &str = opt.1.as_str();
-            if friends.contains(&friend.to_string())
+            let friend_account: Option<Account> = Account::get_account(&friend.to_string()).filter(|x| friends.contains(&x.id));
+            if let Some(friend_account) = friend_account
             {
-                println!("Opening a new conversation with {}", friend.blue());
-                super::message_relay::create_conversation(vec![user.username.clone(), friend.to_string()]);
+                println!("Opening your conversation with {}", friend.blue());
+                let result: Result<String, String> = message_relay::direct_conversation(&user.id, &friend_account.id);
                 utils::clear();
-                draw_convo_list_ui(&user)
+                match result.and_then(|x| Conversation::get(&x).ok_or("Could not find the conversation.".to_string()))
+                {
+                    Ok(convo) => draw_messenger_ui(&user, &convo),
+                    Err(e) =>
+                    {
+                        utils::addl_message(e.as_str(), "red");
+                        draw_messenger_home_ui(&user);
+                    }
+                }
             }
             else
             {
@@ -284,20 +882,45 @@ fn draw_messenger_home_ui(user: &Account)
         }
         "new --multi" =>
         {
-            let user_friends = user.friends.clone();
-            let listed_friends: Vec<&str> = opt.1.split(", ").collect();
-            for friend in friends
+            // an optional group name goes after the list, e.g. "new --multi alice, bob --name project"
+            let (listed_friends, name) = match opt.1.split_once("--name")
             {
-                if !user_friends.contains(&friend.to_string())
+                Some((listed, name)) => (listed, Some(name.trim().to_string()).filter(|x| !x.is_empty())),
+                None => (opt.1.as_str(), None)
+            };
+            let mut members: Vec<String> = vec![user.id.clone()];
+            for friend in listed_friends.split(',').map(|x| x.trim()).filter(|x| !x.is_empty())
+            {
+                match Account::get_account(&friend.to_string()).filter(|x| friends.contains(&x.id))
                 {
-                    utils::clear();
-                    utils::addl_message(format!("You don't have {} added as a friend.", friend.blue()).as_str(), "red");
-                    draw_messenger_home_ui(&user);
-                    return;
+                    Some(friend_account) if !members.contains(&friend_account.id) => members.push(friend_account.id),
+                    Some(_) => {} // listed twice, or it's the user themselves
+                    None =>
+                    {
+                        utils::clear();
+                        utils::addl_message(format!("You don't have {} added as a friend.", friend.blue()).as_str(), "red");
+                        draw_messenger_home_ui(&user);
+                        return;
+                    }
                 }
             }
-            println!("Opening a new conversation with {}", listed_friends.join(", "));
-            panic!("Not yet implemented.")
+            if members.len() < 2
+            {
+                utils::clear();
+                utils::addl_message("List at least one friend to start a conversation with.", "red");
+                draw_messenger_home_ui(&user);
+                return;
+            }
+            println!("Opening a new conversation with {}", listed_friends.trim().blue());
+            let result: Result<String, String> = message_relay::create_conversation(members, name, false);
+            utils::clear();
+            if let Err(e) = result
+            {
+                utils::addl_message(e.as_str(), "red");
+                draw_messenger_home_ui(&user);
+                return;
+            }
+            draw_convo_list_ui(&user)
         }
         "open" =>
         {
@@ -313,10 +936,349 @@ fn draw_messenger_home_ui(user: &Account)
     }
 }
 
+/// Draws the account management panel, where users can change their account details.
 fn manage_account_ui(user: &Account)
 {
-    // TODO: user authentication not proper, so nothing here would be secure yet.
+    require_session();
+    let mut ui: Vec<String> = vec![
+        "Account Management".to_string(),
+        "".to_string(),
+        "".to_string(),
+        "rename <username> : change your username".to_string(),
+        "password : change your password".to_string(),
+        "2fa : turn two-factor authentication on or off".to_string(),
+        "sessions : view and sign out your active sessions".to_string(),
+        "devices : approve or remove the devices that can read your conversations".to_string(),
+        "fingerprint : show your key fingerprint".to_string(),
+        "receipts : turn read receipts on or off".to_string(),
+        "delete : permanently delete your account".to_string(),
+        "back : returns to home page".to_string(),
+    ];
+    if Account::get_account_by_id(&user.id).is_some_and(|x| x.algorithm == crypto::ALGORITHM_RSA)
+    {
+        ui.insert(ui.len() - 2, "upgrade : switch your account to newer, faster keys".to_string());
+    }
+    utils::create_ui(&ui, utils::Position::Center);
+    let opt: (String, String) = utils::grab_opt(Some("Please input your option."), vec!["rename", "password", "2fa", "sessions", "devices", "fingerprint", "receipts", "upgrade", "delete", "back"]);
+    match opt.0.as_str()
+    {
+        "rename" =>
+        {
+            utils::clear();
+            match rename_account(user, opt.1.as_str())
+            {
+                Ok(renamed) =>
+                {
+                    utils::addl_message(format!("Your username is now {}.", renamed.username.blue()).as_str(), "green");
+                    manage_account_ui(&renamed);
+                }
+                Err(e) =>
+                {
+                    utils::addl_message(e.as_str(), "red");
+                    manage_account_ui(user);
+                }
+            }
+        }
+        "password" =>
+        {
+            let old_password: String = utils::grab_secret_input(Some("Type your current password."));
+            let new_password: String = utils::grab_secret_input(Some("Type your new password."));
+            let confirmation: String = utils::grab_secret_input(Some("Type your new password again."));
+            utils::clear();
+            if new_password != confirmation
+            {
+                utils::addl_message("Passwords do not match.", "red");
+                manage_account_ui(user);
+                return;
+            }
+            match login::change_password(user, &old_password, &new_password)
+            {
+                Ok(()) => utils::addl_message("Successfully changed password.", "green"),
+                Err(e) => utils::addl_message(e.as_str(), "red")
+            }
+            manage_account_ui(user);
+        }
+        "2fa" =>
+        {
+            utils::clear();
+            manage_two_factor_ui(user);
+        }
+        "sessions" =>
+        {
+            utils::clear();
+            draw_sessions_ui(user);
+        }
+        "devices" =>
+        {
+            utils::clear();
+            draw_devices_ui(user);
+        }
+        "fingerprint" =>
+        {
+            utils::clear();
+            draw_fingerprint_ui(&Account::get_account_by_id(&user.id).unwrap());
+            manage_account_ui(user);
+        }
+        "receipts" =>
+        {
+            utils::clear();
+            match toggle_read_receipts(user)
+            {
+                Ok(true) => utils::addl_message("Read receipts are on. People can see when you've read their messages.", "green"),
+                Ok(false) => utils::addl_message("Read receipts are off. People can still see when your messages were delivered.", "green"),
+                Err(e) => utils::addl_message(e.as_str(), "red")
+            }
+            manage_account_ui(user);
+        }
+        "upgrade" =>
+        {
+            utils::addl_message("This replaces your keys. Your other devices will be signed out, and will have to be approved again when they next log in.", "red");
+            let password: String = utils::grab_secret_input(Some("Type your password to continue, or \"back\" to cancel."));
+            utils::clear();
+            if password == "back"
+            {
+                manage_account_ui(user);
+                return;
+            }
+            match login::upgrade_keys(user, &password)
+            {
+                Ok(recovery_key) =>
+                {
+                    if let Some(recovery_key) = recovery_key
+                    {
+                        let ui: Vec<String> = vec![
+                            "Your new recovery key".to_string(),
+                            "".to_string(),
+                            recovery_key,
+                            "".to_string(),
+                            "Your old recovery key no longer works. Write this one down and keep it somewhere safe.".to_string(),
+                            "It won't be shown again.".to_string(),
+                        ];
+                        utils::create_ui(&ui, utils::Position::Center);
+                        utils::grab_str_input(Some("Press enter to continue."));
+                        utils::clear();
+                    }
+                    utils::addl_message("Your keys have been upgraded. Your contacts will see your fingerprint change.", "green");
+                }
+                Err(e) => utils::addl_message(e.as_str(), "red")
+            }
+            manage_account_ui(user);
+        }
+        "delete" =>
+        {
+            utils::addl_message("This will permanently delete your account and remove you from all of your conversations.", "red");
+            let password: String = utils::grab_secret_input(Some("Type your password to continue."));
+            let confirmation: String = utils::grab_str_input(Some("Type your username to confirm deletion."));
+            utils::clear();
+            if confirmation != user.username
+            {
+                utils::addl_message("Username did not match. Your account was not deleted.", "red");
+                manage_account_ui(user);
+                return;
+            }
+            match delete_account(user, &password)
+            {
+                Ok(()) =>
+                {
+                    keystore::lock();
+                    keystore::clear_private_key(&user.id).expect("Failed to remove private key.");
+                    account_cache::forget(&user.id);
+                    login::login_init();
+                }
+                Err(e) =>
+                {
+                    utils::addl_message(e.as_str(), "red");
+                    manage_account_ui(user);
+                }
+            }
+        }
+        "back" =>
+        {
+            utils::clear();
+            draw_home_ui(user);
+        }
+        _ => {}
+    }
 }
+/// Draws the list of the user's active sessions, where any of them can be signed out.
+fn draw_sessions_ui(user: &Account)
+{
+    require_session();
+    let sessions: Vec<Session> = session::list(&user.id);
+    let mut ui: Vec<String> = vec!["Active Sessions".to_string(), "".to_string(), "".to_string()];
+    for (i, s) in sessions.iter().enumerate()
+    {
+        let current: &str = if s.is_current() { " (this device)" } else { "" };
+        ui.push(format!("{} : {}{} | signed in {} | last seen {}", i + 1, s.device, current, s.created, s.last_seen));
+    }
+    ui.push("".to_string());
+    ui.push("revoke <number> : sign out a session".to_string());
+    ui.push("back : return to account management".to_string());
+    utils::create_ui(&ui, utils::Position::Center);
+    let opt: (String, String) = utils::grab_opt(Some("Please input your option."), vec!["revoke", "back"]);
+    match opt.0.as_str()
+    {
+        "revoke" =>
+        {
+            utils::clear();
+            let selected: Option<&Session> = opt.1.parse::<usize>().ok().and_then(|x| x.checked_sub(1)).and_then(|x| sessions.get(x));
+            match selected.map(|x| session::revoke(&user.id, &x.id))
+            {
+                Some(Ok(())) => utils::addl_message("Session signed out.", "green"),
+                Some(Err(e)) => utils::addl_message(e.as_str(), "red"),
+                None => utils::addl_message("That session doesn't exist.", "red")
+            }
+            // revoking this device's own session logs out straight away
+            draw_sessions_ui(user);
+        }
+        "back" =>
+        {
+            utils::clear();
+            manage_account_ui(user);
+        }
+        _ => {}
+    }
+}
+
+/// Draws the list of devices on the user's account, where new devices can be approved and old ones removed.
+fn draw_devices_ui(user: &Account)
+{
+    require_session();
+    let devices: Vec<Device> = Account::get_account_by_id(&user.id).unwrap().devices;
+    let this_device: Option<String> = keystore::device_id(&user.id);
+    let mut ui: Vec<String> = vec!["Devices".to_string(), "".to_string(), "".to_string()];
+    for (i, d) in devices.iter().enumerate()
+    {
+        let status: &str = if d.approved { "approved" } else { "waiting for approval" };
+        let current: &str = if this_device.as_ref() == Some(&d.id) { " (this device)" } else { "" };
+        ui.push(format!("{} : {}{} | {} | code {} | added {}", i + 1, d.name, current, status, d.verification_code(), d.created));
+    }
+    ui.push("".to_string());
+    ui.push("approve <number> : let a new device read your conversations".to_string());
+    ui.push("remove <number> : remove a device from your account".to_string());
+    ui.push("back : return to account management".to_string());
+    utils::create_ui(&ui, utils::Position::Center);
+    let opt: (String, String) = utils::grab_opt(Some("Please input your option."), vec!["approve", "remove", "back"]);
+    let selected: Option<&Device> = opt.1.parse::<usize>().ok().and_then(|x| x.checked_sub(1)).and_then(|x| devices.get(x));
+    match opt.0.as_str()
+    {
+        "approve" =>
+        {
+            let confirmation: String = match selected
+            {
+                Some(device) if !device.approved => utils::grab_str_input(Some(format!("Type the verification code shown on {} to approve it.", device.name).as_str())),
+                _ => String::new()
+            };
+            utils::clear();
+            match selected.map(|x| approve_device(user, x, &confirmation))
+            {
+                Some(Ok(())) => utils::addl_message("Device approved.", "green"),
+                Some(Err(e)) => utils::addl_message(e.as_str(), "red"),
+                None => utils::addl_message("That device doesn't exist.", "red")
+            }
+            draw_devices_ui(user);
+        }
+        "remove" =>
+        {
+            utils::clear();
+            match selected.map(|x| remove_device(user, x))
+            {
+                Some(Ok(())) => utils::addl_message("Device removed.", "green"),
+                Some(Err(e)) => utils::addl_message(e.as_str(), "red"),
+                None => utils::addl_message("That device doesn't exist.", "red")
+            }
+            draw_devices_ui(user);
+        }
+        "back" =>
+        {
+            utils::clear();
+            manage_account_ui(user);
+        }
+        _ => {}
+    }
+}
+
+/// Walks the user through turning two-factor authentication on, or off if it's already on.
+fn manage_two_factor_ui(user: &Account)
+{
+    let udata: Account = Account::get_account_by_id(&user.id).unwrap();
+    if udata.totp_secret.is_some()
+    {
+        let code: String = utils::grab_str_input(Some("Two-factor authentication is on. Type a code from your authenticator app or a backup code to turn it off, or \"back\" to cancel."));
+        utils::clear();
+        if code == "back"
+        {
+            manage_account_ui(user);
+            return;
+        }
+        if !login::verify_second_factor(&udata, &code)
+        {
+            utils::addl_message("Invalid two-factor code. Two-factor authentication is still on.", "red");
+            manage_account_ui(user);
+            return;
+        }
+        // re-fetch, since a backup code may have just been used up
+        let mut udata: Account = Account::get_account_by_id(&user.id).unwrap();
+        udata.totp_secret = None;
+        udata.totp_last_step = 0;
+        udata.backup_codes.clear();
+        match Account::update_account(&udata)
+        {
+            Ok(_) => utils::addl_message("Two-factor authentication turned off.", "green"),
+            Err(e) => utils::addl_message(e.to_string().as_str(), "red")
+        }
+        manage_account_ui(user);
+        return;
+    }
+
+    let secret: String = totp::generate_secret();
+    let ui: Vec<String> = vec![
+        "Two-Factor Authentication".to_string(),
+        "".to_string(),
+        "Scan this URI as a QR code with your authenticator app, or enter the secret manually.".to_string(),
+        "".to_string(),
+        totp::otpauth_uri(&udata.username, &secret),
+        format!("secret : {}", secret),
+    ];
+    utils::create_ui(&ui, utils::Position::Center);
+    let code: String = utils::grab_str_input(Some("Type the 6-digit code your app shows to finish setting up."));
+    utils::clear();
+    let step: u64 = match totp::verify(&secret, &code)
+    {
+        Some(step) => step,
+        None =>
+        {
+            utils::addl_message("Code did not match. Two-factor authentication was not turned on.", "red");
+            manage_account_ui(user);
+            return;
+        }
+    };
+    let backup_codes: Vec<String> = totp::generate_backup_codes();
+    let mut udata: Account = udata;
+    udata.totp_secret = Some(secret);
+    // the setup code counts as used, so it can't also be used to log in
+    udata.totp_last_step = step as i64;
+    udata.backup_codes = backup_codes.iter().map(|x| totp::hash_backup_code(x)).collect();
+    if let Err(e) = Account::update_account(&udata)
+    {
+        utils::addl_message(e.to_string().as_str(), "red");
+        manage_account_ui(user);
+        return;
+    }
+    let mut ui: Vec<String> = vec![
+        "Two-factor authentication is on.".to_string(),
+        "".to_string(),
+        "These backup codes can each be used once if you lose your authenticator.".to_string(),
+        "They won't be shown again, so write them down somewhere safe.".to_string(),
+        "".to_string(),
+    ];
+    ui.extend(backup_codes);
+    utils::create_ui(&ui, utils::Position::Center);
+    utils::grab_str_input(Some("Press enter to continue."));
+    utils::clear();
+    manage_account_ui(user);
+}
+
 //---------------------------------------------------------------------//
 //                                                                     //
 //                         Back-End Functions                          //
@@ -325,16 +1287,33 @@ fn manage_account_ui(user: &Account)
 //---------------------------------------------------------------------//
 
 
-/// Adds a friend (username string) to the friends array on the user's account. Returns true if successful, false if not.
+/// Makes sure this client's session is still active. If it was revoked from another device, the private key is locked and the user is sent back to the login screen.
+/// Never returns in that case: whatever screen called it belongs to the old session, so once the login screen is done the program exits rather than going back to it.
+fn require_session()
+{
+    if session::touch()
+    {
+        return;
+    }
+    keystore::lock();
+    utils::clear();
+    utils::addl_message("You've been signed out of this session.", "red");
+    utils::grab_str_input(Some("Press enter to continue."));
+    login::login_init();
+    std::process::exit(0);
+}
+
+/// Adds a friend (by username) to the friends array on the user's account. The friend's account ID is what gets stored. Returns true if successful, false if not.
 fn add_friend(user: &Account, friend: &str) -> bool
 {
     let friend: String = String::from(friend);
-    let mut udata: Account = Account::get_account(&user.username).unwrap(); //should never fail
-    if Account::get_account(&friend).is_none()
+    let mut udata: Account = Account::get_account_by_id(&user.id).unwrap(); //should never fail
+    let friend: String = match Account::get_account(&friend)
     {
-        return false;
+        Some(friend) => friend.id,
+        None => return false
     };
-    if udata.friends.contains(&friend)
+    if udata.friends.contains(&friend) || friend == udata.id
     {
         return false;
     }
@@ -343,12 +1322,17 @@ fn add_friend(user: &Account, friend: &str) -> bool
     // TODO: blocklist? not necessary right now though.
 }
 
-/// Removes a friend (username string) from the friends array on the user's account. Returns true if successful, false if not.
+/// Removes a friend (by username) from the friends array on the user's account. Returns true if successful, false if not.
 fn remove_friend(user: &Account, friend: &str) -> bool
 {
     let friend: String = String::from(friend);
-    let mut udata: Account = Account::get_account(&user.username).unwrap();
-    // shouldn't be any need to check if the friend exists, because that should have been checked when the friend was added.
+    let mut udata: Account = Account::get_account_by_id(&user.id).unwrap();
+    // deleted accounts are pulled from everyone's friends list, so a friend that's still listed will always resolve.
+    let friend: String = match Account::get_account(&friend)
+    {
+        Some(friend) => friend.id,
+        None => return false
+    };
     if !udata.friends.contains(&friend)
     {
         return false;
@@ -358,6 +1342,79 @@ fn remove_friend(user: &Account, friend: &str) -> bool
 }
 
 
+/// Changes the username on the user's account. Friends and conversations reference the account ID, so nothing else needs updating. Returns the updated account.
+fn rename_account(user: &Account, new_username: &str) -> Result<Account, String>
+{
+    let new_username: String = new_username.to_string();
+    if new_username.is_empty() || new_username.contains(char::is_whitespace)
+    {
+        return Err("Usernames can't be empty or contain spaces.".to_string());
+    }
+    if Account::get_account(&new_username).is_some()
+    {
+        return Err(format!("The username {} is already taken.", new_username.blue()));
+    }
+    let mut udata: Account = Account::get_account_by_id(&user.id).ok_or("Your account could not be found.")?;
+    udata.username = new_username;
+    Account::update_account(&udata).map_err(|e| e.to_string())
+}
+
+/// Turns the user's read receipts on or off, and returns the new setting.
+fn toggle_read_receipts(user: &Account) -> Result<bool, String>
+{
+    let mut udata: Account = Account::get_account_by_id(&user.id).ok_or("Your account could not be found.")?;
+    udata.read_receipts = !udata.read_receipts;
+    Account::update_account(&udata)
+        .map(|x| x.read_receipts)
+        .map_err(|e| e.to_string())
+}
+
+/// Approves a device waiting for approval, once the user has typed in the verification code it's showing, and gives it every conversation key this device has.
+fn approve_device(user: &Account, device: &Device, code: &str) -> Result<(), String>
+{
+    if device.approved
+    {
+        return Err("That device is already approved.".to_string());
+    }
+    if code.trim().to_uppercase() != device.verification_code()
+    {
+        return Err("The verification code didn't match. The device was not approved.".to_string());
+    }
+    // share the keys first, so the device can read everything as soon as it's let in
+    message_relay::share_keys_with_device(&user.id, device)?;
+    match Account::approve_device(&user.id, &device.id)
+    {
+        Ok(true) => Ok(()),
+        Ok(false) => Err("That device was removed before it could be approved.".to_string()),
+        Err(e) => Err(e.to_string())
+    }
+}
+
+/// Removes a device from the user's account, along with its conversation keys. The device this client is using can't be removed from here; log out instead.
+fn remove_device(user: &Account, device: &Device) -> Result<(), String>
+{
+    if keystore::device_id(&user.id).as_ref() == Some(&device.id)
+    {
+        return Err("You can't remove the device you're using.".to_string());
+    }
+    Account::remove_device(&user.id, &device.id).map_err(|e| e.to_string())?;
+    message_relay::remove_device_keys(&user.id, &device.id)
+}
+
+/// Deletes an account after re-checking its password. The user is removed from everyone's friends list and from every conversation before the account itself is deleted.
+fn delete_account(user: &Account, password: &str) -> Result<(), String>
+{
+    let account: Account = Account::get_account_by_id(&user.id).ok_or("Your account could not be found.")?;
+    if !login::verify_password(&account, password)
+    {
+        return Err("Incorrect password. Your account was not deleted.".to_string());
+    }
+    Account::remove_from_friend_lists(&account.id).map_err(|e| e.to_string())?;
+    message_relay::remove_user_from_conversations(&account.id)?;
+    session::end_all(&account.id).map_err(|e| e.to_string())?;
+    Account::delete_account(&account.id).map_err(|e| e.to_string())
+}
+
 //----------------------------------------------//
 //                                              //
 //                Initialization                //
@@ -367,7 +1424,7 @@ fn remove_friend(user: &Account, friend: &str) -> bool
 /// Initializes the messenger panel with a given account.
 pub fn init(account: &Account)
 {
-    if let Some(user) = Account::get_account(&account.username)
+    if let Some(user) = Account::get_account_by_id(&account.id)
     {
         draw_home_ui(&user);
         // &user is passed around like herpes. May be a better way to store it.
@@ -378,3 +1435,35 @@ pub fn init(account: &Account)
         panic!("Opened the messenger with an invalid profile... How?")
     }
 }
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn shortcodes_become_emoji()
+    {
+        assert_eq!(reaction_emoji(":thumbsup:"), Some("👍".to_string()));
+        assert_eq!(reaction_emoji(":+1:"), Some("👍".to_string()));
+        assert_eq!(reaction_emoji(":tada:"), Some("🎉".to_string()));
+        assert_eq!(reaction_emoji(":nonsense:"), None);
+        assert_eq!(reaction_emoji("::"), None);
+    }
+
+    #[test]
+    fn emoji_can_be_typed_directly()
+    {
+        assert_eq!(reaction_emoji("🔥"), Some("🔥".to_string()));
+        assert_eq!(reaction_emoji("❤️"), Some("❤️".to_string()));
+    }
+
+    #[test]
+    fn text_is_not_a_reaction()
+    {
+        assert_eq!(reaction_emoji(""), None);
+        assert_eq!(reaction_emoji("lol"), None);
+        assert_eq!(reaction_emoji("👍 👍"), None);
+        assert_eq!(reaction_emoji("🎉🎉🎉🎉🎉🎉🎉🎉🎉"), None);
+    }
+}
@@ -1,3 +1,3 @@
 pub mod messenger_panel;
 pub mod message_relay;
-use crate::core::{mongo, structs, utils, login};
\ No newline at end of file
+use crate::core::{account_cache, crypto, fingerprint, key_log, keystore, mongo, session, structs, utils, login, totp};
\ No newline at end of file